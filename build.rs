@@ -1,4 +1,4 @@
-use std::{env, fs, path::{Path, PathBuf}};
+use std::{collections::BTreeMap, env, fmt::Write, fs, path::{Path, PathBuf}};
 use chrono::Utc;
 
 use dotenv::{dotenv, vars};
@@ -24,5 +24,44 @@ fn main() -> Result<(), anyhow::Error> {
 
     println!("cargo:rustc-env=TARGET_TRIPLE={}", env::var("TARGET")?);
 
+    generate_zones(&out_dir)?;
+
+    Ok(())
+}
+
+/// Writes `zones.rs` with the IANA zone names in `zones.csv` and their POSIX TZ rules for
+/// [`tz`](src/tz.rs). Zones sorted by name index into a list of the distinct rules as many share them
+fn generate_zones(out_dir: &Path) -> Result<(), anyhow::Error> {
+    let csv = fs::read_to_string("zones.csv")?;
+
+    let mut zones = BTreeMap::new();
+    let mut rules: Vec<&str> = Vec::new();
+    for line in csv.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (name, rule) = line
+            .split_once(',')
+            .ok_or_else(|| anyhow::anyhow!("zones.csv: expected <name>,<rule>, got {line:?}"))?;
+        let index = match rules.iter().position(|&r| r == rule) {
+            Some(index) => index,
+            None => {
+                rules.push(rule);
+                rules.len() - 1
+            },
+        };
+        if zones.insert(name, index).is_some() {
+            anyhow::bail!("zones.csv: {name} appears twice");
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "/// Distinct POSIX TZ rules of the zones")?;
+    writeln!(out, "pub static RULES: [&str; {}] = {rules:?};", rules.len())?;
+    writeln!(out, "/// IANA zone names sorted for binary search, with the index of their rule in `RULES`")?;
+    writeln!(out, "pub static ZONES: [(&str, u16); {}] = [", zones.len())?;
+    for (name, index) in zones {
+        writeln!(out, "    ({name:?}, {index}),")?;
+    }
+    writeln!(out, "];")?;
+
+    fs::write(out_dir.join("zones.rs"), out)?;
     Ok(())
 }
@@ -0,0 +1,416 @@
+//! Motors that can move the blind, either a stepper behind a STEP/DIR driver or a DC motor behind an
+//! H-bridge with a quadrature encoder
+//!
+//! Positions are in abstract units, steps for a stepper and encoder counts (divided down) for a DC
+//! motor. The motor task only ever deals in these units so calibration, limits and schedules work
+//! the same whichever motor is fitted.
+
+use esp_hal::gpio::{Level, Output};
+#[cfg(feature = "dc-motor")]
+use {
+    crate::dc_motor::{DutyRamp, EncoderCount},
+    embassy_time::{Duration, Instant, Timer},
+    esp_hal::{
+        gpio::Input,
+        ledc::{
+            channel::{self, ChannelIFace},
+            LowSpeed,
+        },
+        pcnt::{channel::{CtrlMode, EdgeMode}, unit::Unit},
+    },
+    log::{debug, error},
+};
+
+use crate::{
+    motion::{MotionProfile, StepPlanner},
+    stepper::{self, StepOutput},
+};
+
+/// Largest batch of toggles handed to a [`StepOutput`] at once
+const MAX_BATCH: usize = 96;
+
+/// How far a call to [`BlindActuator::advance`] got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Moving,
+    Done,
+    /// The motor stopped turning while being driven
+    Stalled,
+}
+
+/// A motor that the motor task can drive. Moves are started with
+/// [`start_move`][Self::start_move] and then driven by calling [`advance`][Self::advance] until
+/// it's no longer [`Progress::Moving`], which lets the caller check for stop requests and limits in
+/// between
+#[allow(async_fn_in_trait)]
+pub trait BlindActuator {
+    /// Whether [`advance`][Self::advance] can report [`Progress::Stalled`]
+    fn detects_stalls(&self) -> bool;
+
+    /// Starts moving `distance` units, up (towards 0) or down. Speeds in `profile` are in units/s
+    fn start_move(&mut self, up: bool, distance: u32, profile: &MotionProfile);
+
+    /// Drives the current move a little further
+    async fn advance(&mut self) -> Result<Progress, Error>;
+
+    /// Brings the current move to a stop as quickly as the motor allows. Keep calling
+    /// [`advance`][Self::advance] until it's done
+    fn stop(&mut self);
+
+    /// Releases the motor and returns how many units it moved in the requested direction. Can be
+    /// called part way through a move to abandon it
+    fn end_move(&mut self) -> u32;
+}
+
+/// A stepper driver controlled by STEP, DIR and EN pins
+pub struct StepDirActuator<S> {
+    stepper: S,
+    /// High moves the blind up, towards 0
+    dir: Output<'static>,
+    /// Active low
+    en: Output<'static>,
+    microsteps: u32,
+    /// Microseconds of toggles handed to the stepper at once
+    batch_us: u32,
+    planner: Option<StepPlanner>,
+    toggled: u32,
+}
+
+impl<S: StepOutput> StepDirActuator<S> {
+    /// `batch_us` is roughly the time between checks of the caller's limits. Toggles are batched
+    /// until they add up to at least that long, so a single toggle longer than it makes a batch on
+    /// its own. Use `u32::MAX` when nothing needs checking quickly to batch as many as the
+    /// [`StepOutput`] takes
+    pub fn new(stepper: S, dir: Output<'static>, en: Output<'static>, microsteps: u32, batch_us: u32) -> Self {
+        Self { stepper, dir, en, microsteps, batch_us, planner: None, toggled: 0 }
+    }
+}
+
+impl<S: StepOutput> BlindActuator for StepDirActuator<S> {
+    fn detects_stalls(&self) -> bool {
+        // StallGuard is reported on the driver's DIAG pin rather than through the actuator
+        false
+    }
+
+    fn start_move(&mut self, up: bool, distance: u32, profile: &MotionProfile) {
+        self.dir.set_level(if up { Level::High } else { Level::Low });
+        self.en.set_low();
+
+        let profile = profile.scaled(self.microsteps as f32);
+        self.planner = Some(StepPlanner::new(&profile, distance * self.microsteps));
+        self.toggled = 0;
+    }
+
+    async fn advance(&mut self) -> Result<Progress, Error> {
+        let Some(planner) = self.planner.as_mut() else {
+            return Ok(Progress::Done);
+        };
+
+        let mut batch = [0; MAX_BATCH];
+        let mut len = 0;
+        let mut duration = 0u32;
+        while len < S::BATCH.min(MAX_BATCH) && duration < self.batch_us {
+            let Some(interval) = planner.next() else {
+                break;
+            };
+            batch[len] = interval;
+            len += 1;
+            duration = duration.saturating_add(interval);
+        }
+
+        if len == 0 {
+            return Ok(Progress::Done);
+        }
+
+        self.stepper.toggle(&batch[..len]).await?;
+        self.toggled += len as u32;
+        Ok(Progress::Moving)
+    }
+
+    fn stop(&mut self) {
+        if let Some(planner) = self.planner.as_mut() {
+            // Ending on a whole step keeps the position accurate
+            planner.stop(self.microsteps);
+        }
+    }
+
+    fn end_move(&mut self) -> u32 {
+        self.en.set_high();
+        self.planner = None;
+        self.toggled / self.microsteps
+    }
+}
+
+/// Counter limit, the counter wraps back to 0 when it gets here
+#[cfg(feature = "dc-motor")]
+const ENCODER_LIMIT: i16 = 30_000;
+/// Ignore encoder glitches shorter than this many APB clock cycles (80 MHz)
+#[cfg(feature = "dc-motor")]
+const ENCODER_FILTER: u16 = 1023;
+/// How often the encoder is sampled and the duty updated while moving
+#[cfg(feature = "dc-motor")]
+const DC_POLL: Duration = Duration::from_millis(5);
+
+/// Settings for [`DcMotorActuator`]
+#[cfg(feature = "dc-motor")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DcMotorConfig {
+    /// Encoder counts per position unit
+    pub counts_per_unit: u32,
+    /// Speed in units/s at 100% duty, used to pick the duty for the speed a move asks for
+    pub full_speed: f32,
+    /// Encoder counts increase as the blind is lowered
+    pub counts_down: bool,
+    /// Duty ramp with `max_duty` being the most any move will use and `slowdown` in units
+    pub ramp: DutyRamp,
+    /// Report a stall if the encoder doesn't move for this long while driven after the soft start
+    pub stall_timeout: Duration,
+}
+
+#[cfg(feature = "dc-motor")]
+struct DcMove {
+    up: bool,
+    /// Target distance in counts
+    distance: u32,
+    start_count: i64,
+    ramp: DutyRamp,
+    started: Instant,
+    stopping: Option<(Instant, u8)>,
+    last_count: i64,
+    last_moved: Instant,
+}
+
+#[cfg(feature = "dc-motor")]
+impl DcMove {
+    /// Distance in counts moved in the direction of the move
+    fn travelled(&self, count: i64, counts_down: bool) -> u32 {
+        let moved = count - self.start_count;
+        let moved = if self.up == counts_down { -moved } else { moved };
+        moved.max(0) as u32
+    }
+}
+
+/// A DC motor driven through an H-bridge (PWM on the enable input, direction on IN1 and IN2) with a
+/// quadrature encoder on PCNT unit 0
+#[cfg(feature = "dc-motor")]
+pub struct DcMotorActuator {
+    pwm: channel::Channel<'static, LowSpeed>,
+    in1: Output<'static>,
+    in2: Output<'static>,
+    unit: Unit<'static, 0>,
+    encoder: EncoderCount,
+    config: DcMotorConfig,
+    duty: u8,
+    current: Option<DcMove>,
+}
+
+#[cfg(feature = "dc-motor")]
+impl DcMotorActuator {
+    pub fn new(
+        pwm: channel::Channel<'static, LowSpeed>,
+        in1: Output<'static>,
+        in2: Output<'static>,
+        unit: Unit<'static, 0>,
+        encoder_a: Input<'static>,
+        encoder_b: Input<'static>,
+        config: DcMotorConfig,
+    ) -> Result<Self, Error> {
+        unit.set_low_limit(Some(-ENCODER_LIMIT))?;
+        unit.set_high_limit(Some(ENCODER_LIMIT))?;
+        unit.set_filter(Some(ENCODER_FILTER))?;
+        unit.clear();
+
+        // Count every edge of both signals in full quadrature
+        let a = encoder_a.peripheral_input();
+        let b = encoder_b.peripheral_input();
+        let channel0 = &unit.channel0;
+        channel0.set_ctrl_signal(a.clone());
+        channel0.set_edge_signal(b.clone());
+        channel0.set_ctrl_mode(CtrlMode::Reverse, CtrlMode::Keep);
+        channel0.set_input_mode(EdgeMode::Increment, EdgeMode::Decrement);
+        let channel1 = &unit.channel1;
+        channel1.set_ctrl_signal(b);
+        channel1.set_edge_signal(a);
+        channel1.set_ctrl_mode(CtrlMode::Reverse, CtrlMode::Keep);
+        channel1.set_input_mode(EdgeMode::Decrement, EdgeMode::Increment);
+        unit.resume();
+        debug!("Encoder configured");
+
+        let mut actuator = Self {
+            pwm,
+            in1,
+            in2,
+            unit,
+            encoder: EncoderCount::new(ENCODER_LIMIT),
+            config,
+            duty: 0,
+            current: None,
+        };
+        actuator.brake()?;
+        Ok(actuator)
+    }
+
+    fn count(&mut self) -> i64 {
+        self.encoder.update(self.unit.value())
+    }
+
+    fn set_duty(&mut self, duty: u8) -> Result<(), Error> {
+        if duty != self.duty {
+            self.pwm.set_duty(duty)?;
+            self.duty = duty;
+        }
+        Ok(())
+    }
+
+    /// Shorts the motor terminals so it stops quickly and holds against the weight of the blind
+    fn brake(&mut self) -> Result<(), Error> {
+        self.set_duty(0)?;
+        self.in1.set_high();
+        self.in2.set_high();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl BlindActuator for DcMotorActuator {
+    fn detects_stalls(&self) -> bool {
+        true
+    }
+
+    fn start_move(&mut self, up: bool, distance: u32, profile: &MotionProfile) {
+        let DcMotorConfig { counts_per_unit, full_speed, ramp, .. } = self.config;
+
+        // Open loop, slower moves (such as homing) get a proportionally lower duty
+        let fraction = (profile.max_speed / full_speed).clamp(0., 1.);
+        let max_duty = ((ramp.max_duty as f32 * fraction) as u8).max(ramp.min_duty);
+        let ramp = DutyRamp { max_duty, slowdown: ramp.slowdown * counts_per_unit, ..ramp };
+
+        if up {
+            self.in1.set_high();
+            self.in2.set_low();
+        } else {
+            self.in1.set_low();
+            self.in2.set_high();
+        }
+
+        let now = Instant::now();
+        let count = self.count();
+        self.current = Some(DcMove {
+            up,
+            distance: distance * counts_per_unit,
+            start_count: count,
+            ramp,
+            started: now,
+            stopping: None,
+            last_count: count,
+            last_moved: now,
+        });
+    }
+
+    async fn advance(&mut self) -> Result<Progress, Error> {
+        let count = self.count();
+        let DcMotorConfig { counts_down, stall_timeout, .. } = self.config;
+        let Some(current) = self.current.as_mut() else {
+            return Ok(Progress::Done);
+        };
+
+        // The low duty at the start of the soft start may not turn the motor so the stall timeout is
+        // only counted from the end of it
+        let now = Instant::now();
+        let soft_start = Duration::from_millis(current.ramp.soft_start_ms);
+        if count != current.last_count || now - current.started < soft_start {
+            current.last_count = count;
+            current.last_moved = now;
+        }
+        let stalled = now - current.last_moved >= stall_timeout;
+
+        let travelled = current.travelled(count, counts_down);
+        let duty = match current.stopping {
+            Some((since, from)) => current.ramp.stopping_duty(from, (now - since).as_millis()),
+            None if travelled >= current.distance => 0,
+            None => current.ramp.duty((now - current.started).as_millis(), current.distance - travelled),
+        };
+
+        if duty == 0 {
+            self.brake()?;
+            return Ok(Progress::Done);
+        }
+        if stalled {
+            self.brake()?;
+            return Ok(Progress::Stalled);
+        }
+
+        self.set_duty(duty)?;
+        Timer::after(DC_POLL).await;
+        Ok(Progress::Moving)
+    }
+
+    fn stop(&mut self) {
+        let duty = self.duty;
+        if let Some(current) = self.current.as_mut() {
+            if current.stopping.is_none() {
+                current.stopping = Some((Instant::now(), duty));
+            }
+        }
+    }
+
+    fn end_move(&mut self) -> u32 {
+        if let Err(e) = self.brake() {
+            error!("Failed to brake motor: {e:?}");
+        }
+
+        let count = self.count();
+        let Some(current) = self.current.take() else {
+            return 0;
+        };
+        // The motor may coast a little past the target after braking, the encoder still counts it
+        current.travelled(count, self.config.counts_down) / self.config.counts_per_unit
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Stepper(stepper::Error),
+    #[cfg(feature = "dc-motor")]
+    Pwm(channel::Error),
+    #[cfg(feature = "dc-motor")]
+    EncoderFilter(esp_hal::pcnt::unit::InvalidFilterThreshold),
+    #[cfg(feature = "dc-motor")]
+    EncoderLowLimit(esp_hal::pcnt::unit::InvalidLowLimit),
+    #[cfg(feature = "dc-motor")]
+    EncoderHighLimit(esp_hal::pcnt::unit::InvalidHighLimit),
+}
+
+impl From<stepper::Error> for Error {
+    fn from(value: stepper::Error) -> Self {
+        Self::Stepper(value)
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl From<channel::Error> for Error {
+    fn from(value: channel::Error) -> Self {
+        Self::Pwm(value)
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl From<esp_hal::pcnt::unit::InvalidFilterThreshold> for Error {
+    fn from(value: esp_hal::pcnt::unit::InvalidFilterThreshold) -> Self {
+        Self::EncoderFilter(value)
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl From<esp_hal::pcnt::unit::InvalidLowLimit> for Error {
+    fn from(value: esp_hal::pcnt::unit::InvalidLowLimit) -> Self {
+        Self::EncoderLowLimit(value)
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl From<esp_hal::pcnt::unit::InvalidHighLimit> for Error {
+    fn from(value: esp_hal::pcnt::unit::InvalidHighLimit) -> Self {
+        Self::EncoderHighLimit(value)
+    }
+}
@@ -3,17 +3,19 @@
 #![feature(sync_unsafe_cell)]
 #![feature(impl_trait_in_assoc_type)]
 
-use core::{convert::Infallible, fmt::Write, num::ParseIntError, str::Utf8Error};
+use core::{cell::Cell, convert::Infallible, fmt::Write, num::ParseIntError, str::Utf8Error};
 use embedded_io_async::{Read, Write as EioWrite};
-use blind_controller::{http::{self, CallbackError}, logging, ntp, nvs::{self, Nvs, MIN_OFFSET}, ota::{self, Ota}, partitions::{ NVS_PARTITION, OTA_0_PARTITION, OTA_1_PARTITION}, rtc::enter_deep as enter_deep_sleep, system_time::SystemTime, wifi::{self, PASSWORD_LEN, SSID_MAX_LEN}};
-use chrono::{NaiveDate, TimeZone, Timelike};
+use blind_controller::{actuator::{self, BlindActuator, Progress}, http::{self, CallbackError}, endstop::Endstops, logging, schedule::{self, Activity, Day, Glare, Hold, Rule, Rules, Target, Trigger, Upcoming, Vacation}, scene::{self, Scene, Scenes}, solar::{self, Coordinates, SolarEvent}, motion::MotionProfile, ntp, one_shot::{self, OneShot, TimerRequest, Timers}, nvs::{self, Nvs, MIN_OFFSET}, ota::{self, Ota}, partitions::{ NVS_PARTITION, OTA_0_PARTITION, OTA_1_PARTITION}, rng::RngWrapper, rtc::enter_deep as enter_deep_sleep, stepper, system_time::SystemTime, tmc::{self, Tmc}, tz, wifi::{self, PASSWORD_LEN, SSID_MAX_LEN}};
+use chrono::TimeZone;
 use const_format::concatcp;
+use rand_core::RngCore;
 use embassy_executor::Spawner;
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex,
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
     channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 // For panic-handler
 use esp_backtrace as _;
 use esp_hal::{
@@ -24,18 +26,34 @@ use esp_hal::{
     rng::Rng,
     rtc_cntl::{Rtc, SocResetReason},
     timer::timg::TimerGroup,
+    uart::{self, Config as UartConfig, Uart},
+    Async,
     Config as HalConfig,
 };
 use esp_hal_embassy::main;
+#[cfg(any(feature = "endstops", feature = "dc-motor", feature = "stallguard", feature = "button", feature = "buttons"))]
+use esp_hal::gpio::{Input, Pull};
+#[cfg(feature = "endstops")]
+use blind_controller::endstop::Endstop;
+#[cfg(any(feature = "button", feature = "buttons"))]
+use blind_controller::gesture::{Gesture, GestureDetector};
+#[cfg(feature = "dc-motor")]
+use {
+    blind_controller::{actuator::DcMotorConfig, dc_motor::DutyRamp},
+    esp_hal::{
+        ledc::{channel::{self as ledc_channel, ChannelIFace}, timer::{self as ledc_timer, TimerIFace}, LSGlobalClkSource, Ledc, LowSpeed},
+        pcnt::Pcnt,
+        time::RateExtU32,
+    },
+};
 use esp_storage::FlashStorage;
-use heapless::{String, Vec};
+use heapless::{Deque, String, Vec};
 use log::*;
 use picoserve::{
     response::{Content, IntoResponse, ResponseWriter}, routing::{get, parse_path_segment}, AppBuilder, AppRouter
 };
 use embassy_sync::mutex::Mutex;
-use sunrise::{Coordinates, SolarDay, SolarEvent};
-use time::{error::ComponentRange, Date, Month, OffsetDateTime, Time, UtcOffset, Weekday};
+use time::{error::ComponentRange, Date, OffsetDateTime, UtcOffset};
 
 static UPDATE_PENDING: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
 
@@ -61,7 +79,18 @@ const WEB_TASK_POOL_SIZE: usize = 2; // wifi::STACK_SOCKET_COUNT - 3;
 
 const SSID: Option<&str> = option_env!("SSID");
 const PASSWORD: Option<&str> = option_env!("PASSWORD");
-const NTP_SERVER: &str = env!("NTP_SERVER");
+/// Comma separated NTP server names, from the `NTP_SERVERS` env variable or the older `NTP_SERVER`
+const NTP_SERVERS: &str = match option_env!("NTP_SERVERS") {
+    Some(v) => v,
+    None => match option_env!("NTP_SERVER") {
+        Some(v) => v,
+        None => "pool.ntp.org",
+    },
+};
+/// Time between NTP updates once the clock is synchronized
+const NTP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// First retry after a failed NTP update, doubling with each failure in a row up to `NTP_INTERVAL`
+const NTP_MIN_RETRY: Duration = Duration::from_secs(10);
 
 const BUILD_DATE: i64 = { match i64::from_str_radix(env!("BUILD_DATE"), 10) {
     Ok(v) => v,
@@ -76,6 +105,8 @@ const LATITUDE: &str = env!("LATITUDE");
 const LONGITUDE: &str = env!("LONGITUDE");
 // const LAT_LONG: Coordinates = { match }
 
+/// Default travel in steps between fully raised (position 0) and fully lowered. Used until the
+/// travel has been calibrated at runtime
 const BLIND_HEIGHT: usize = 4450;
 
 macro_rules! mk_static {
@@ -87,14 +118,226 @@ macro_rules! mk_static {
     }};
 }
 
+type SharedFlash = Mutex<CriticalSectionRawMutex, FlashStorage>;
+type SharedDriver = Mutex<CriticalSectionRawMutex, Tmc<Uart<'static, Async>>>;
+
+type CommandChannel = Channel<CriticalSectionRawMutex, StepCommand, 10>;
+type CommandSender = Sender<'static, CriticalSectionRawMutex, StepCommand, 10>;
+type CommandReceiver = Receiver<'static, CriticalSectionRawMutex, StepCommand, 10>;
+type Senders = [CommandSender; BLIND_COUNT];
+
+/// Number of blinds driven by this controller, set with the `BLIND_COUNT` env variable. Blinds
+/// after the first are always GPIO stepped STEP/DIR steppers without endstops or stall detection
+const BLIND_COUNT: usize = match option_env!("BLIND_COUNT") {
+    None => 1,
+    Some(v) => match usize::from_str_radix(v, 10) {
+        Ok(v) => v,
+        Err(_) => panic!("BLIND_COUNT env variable failed to parse as usize"),
+    },
+};
+/// Blinds there are pins assigned for in `main_fallible`
+const MAX_BLINDS: usize = 3;
+const _: () = assert!(BLIND_COUNT >= 1 && BLIND_COUNT <= MAX_BLINDS && BLIND_COUNT <= nvs::MAX_BLINDS, "BLIND_COUNT out of range");
+const _: () = assert!(
+    BLIND_COUNT == 1 || !cfg!(any(feature = "rmt", feature = "dc-motor")),
+    "The rmt and dc-motor features only support a single blind",
+);
+#[cfg(all(feature = "dc-motor", any(feature = "tmc-uart", feature = "stallguard")))]
+compile_error!("The tmc-uart and stallguard features configure a stepper driver and can't be used with dc-motor");
+
+/// TMC2208 is always 0, TMC2209 takes it's address from MS1 (bit 0) and MS2 (bit 1) which are
+/// driven to match
+#[cfg(feature = "tmc-uart")]
+const TMC_ADDRESS: u8 = 0;
+#[cfg(feature = "tmc-uart")]
+const _: () = assert!(TMC_ADDRESS <= 3, "TMC_ADDRESS out of range");
+#[cfg(feature = "tmc-uart")]
+const TMC_BAUD: u32 = 115_200;
+/// Time allowed for a whole exchange with the driver before giving up on it
+const TMC_TIMEOUT: Duration = Duration::from_millis(100);
+#[cfg(feature = "tmc-uart")]
+const TMC_CONFIG: tmc::Config = tmc::Config {
+    microsteps: MICROSTEPS as u16,
+    run_current: 16,
+    hold_current: 4,
+    hold_delay: 8,
+    stealth_chop: true,
+};
+/// A stall is reported when SG_RESULT drops below twice this. Tune it by watching `/driver` while
+/// the blind moves freely and while it's held
+#[cfg(feature = "stallguard")]
+const STALL_THRESHOLD: u8 = 60;
+/// Only report stalls above roughly 100 full steps/s (TSTEP = 12MHz / (256 * steps/s))
+#[cfg(feature = "stallguard")]
+const STALL_MIN_TSTEP: u32 = 470;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "dc-motor")] {
+        type Actuator = actuator::DcMotorActuator;
+    } else if #[cfg(feature = "rmt")] {
+        type Actuator = actuator::StepDirActuator<stepper::RmtStepper>;
+    } else {
+        type Actuator = actuator::StepDirActuator<stepper::GpioStepper>;
+    }
+}
+
+/// Position units are encoder counts divided by `counts_per_unit`, chosen so the travel and speeds
+/// are in the same range as the stepper's
+#[cfg(feature = "dc-motor")]
+const DC_MOTOR_CONFIG: DcMotorConfig = DcMotorConfig {
+    counts_per_unit: 4,
+    full_speed: 500.,
+    counts_down: true,
+    ramp: DutyRamp { min_duty: 25, max_duty: 90, soft_start_ms: 500, slowdown: 100 },
+    stall_timeout: Duration::from_millis(250),
+};
+/// PWM frequency above the audible range
+#[cfg(feature = "dc-motor")]
+const DC_PWM_KHZ: u32 = 20;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum StepCommand {
     Forward(usize),
     Backward(usize),
     Raise,
     Lower,
+    /// Move to a percentage of the travel, 0 = fully raised, 100 = fully lowered
+    MoveTo(u8),
+    /// Move to an absolute position in steps
+    MoveToSteps(usize),
+    /// A step of a gentle move. Moves like [`Self::MoveTo`] but the position isn't saved until the
+    /// next command, which `schedule_task` sends when the gentle move finishes
+    GentleStep(u8),
+    /// Decelerate and abandon the current move, discarding any queued commands
+    Stop,
+    /// Start calibrating the travel. Only jogs are accepted until both ends have been marked
+    Calibrate,
+    /// Mark the current position as the top, then the bottom, of the travel
+    Mark,
+    /// Abandon calibration, keeping the previous travel
+    CancelCalibration,
+    /// Drive slowly up until the top endstop trips (or the motor stalls if there's no endstop) then
+    /// zero the position
+    Home,
+    /// Clear a fault so normal moves are accepted again
+    ClearFault,
+}
+
+impl StepCommand {
+    /// Whether someone sending this holds off the schedule
+    fn holds_schedule(&self) -> bool {
+        matches!(
+            self,
+            Self::Forward(_)
+                | Self::Backward(_)
+                | Self::Raise
+                | Self::Lower
+                | Self::MoveTo(_)
+                | Self::MoveToSteps(_)
+                | Self::Stop
+                | Self::Home
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    /// The driver reported a stall, most likely the blind is obstructed
+    Stall { position: i32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MotorStatus {
+    position: i32,
+    height: usize,
+    moving: bool,
+    calibration: Option<Calibration>,
+    fault: Option<Fault>,
+}
+
+/// Latest state published by each blind's `motor_task`
+static MOTOR_STATUS: [BlockingMutex<CriticalSectionRawMutex, Cell<MotorStatus>>; BLIND_COUNT] = [const {
+    BlockingMutex::new(Cell::new(MotorStatus {
+        position: 0,
+        height: BLIND_HEIGHT,
+        moving: false,
+        calibration: None,
+        fault: None,
+    }))
+}; BLIND_COUNT];
+
+fn motor_status(id: usize) -> MotorStatus {
+    MOTOR_STATUS[id].lock(|status| status.get())
+}
+
+/// How long a manual command holds off the schedule for, even if it changes in the meantime. Unset
+/// to hold until the next scheduled change instead
+const OVERRIDE_MINUTES: Option<u32> = match option_env!("OVERRIDE_MINUTES") {
+    None => None,
+    Some(v) => match u32::from_str_radix(v, 10) {
+        Ok(v) => Some(v),
+        Err(_) => panic!("OVERRIDE_MINUTES env variable failed to parse as u32"),
+    },
+};
+
+/// Schedule hold of each blind, set by manual commands and cleared by `schedule_task`
+static HOLD: [BlockingMutex<CriticalSectionRawMutex, Cell<Option<Hold>>>; BLIND_COUNT] =
+    [const { BlockingMutex::new(Cell::new(None)) }; BLIND_COUNT];
+
+fn hold(id: usize) -> Option<Hold> {
+    HOLD[id].lock(|hold| hold.get())
+}
+
+/// Sends a command from a button, the web interface or a timer, holding off the schedule of blind
+/// `id` if the command moves it
+async fn send_manual(sender: CommandSender, id: usize, command: StepCommand) {
+    if command.holds_schedule() {
+        if let Ok(now) = (SystemTime {}).datetime() {
+            let since = now.unix_timestamp();
+            let until = OVERRIDE_MINUTES.map(|minutes| since + minutes as i64 * 60);
+            HOLD[id].lock(|hold| hold.set(Some(Hold { since, until })));
+        }
+    }
+    sender.send(command).await;
+}
+
+/// Set by `stall_task` when the driver of the first blind raises DIAG
+static STALL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The end of the travel the next [`StepCommand::Mark`] records
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Calibration {
+    Top,
+    Bottom,
 }
 
+/// Microsteps per full step the driver is configured for
+const MICROSTEPS: usize = 2;
+
+/// Motion profile in steps per second. It's scaled by the microstep setting to get step pin
+/// toggles per second
+const MOTION_PROFILE: MotionProfile = MotionProfile {
+    start_speed: 100.,
+    max_speed: 333.,
+    acceleration: 500.,
+    jerk: 2500.,
+};
+
+/// Homing creeps towards the endstop at the start speed of the normal profile
+const HOMING_PROFILE: MotionProfile = MotionProfile::constant(MOTION_PROFILE.start_speed);
+
+/// Sensorless homing has to move quickly enough for StallGuard to work
+const SENSORLESS_HOMING_PROFILE: MotionProfile = MotionProfile::constant(200.);
+
+/// Stalls are ignored at the start of a move while the motor is too slow for StallGuard to be
+/// reliable
+const STALL_BLANKING: Duration = Duration::from_millis(300);
+
+/// Time covered by each batch of toggles while an endstop is being watched, so it's sampled about
+/// this often at cruise speed. Homing toggles every 5ms which sets the slowest rate
+#[allow(unused)]
+const ENDSTOP_BATCH_US: u32 = 2_000;
+
 #[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
 async fn web_task(
     id: usize,
@@ -121,8 +364,11 @@ async fn web_task(
 }
 
 struct AppProps {
-    sender: Sender<'static, CriticalSectionRawMutex, StepCommand, 10>,
+    senders: Senders,
+    flash: &'static SharedFlash,
     coordinates: Coordinates,
+    driver: Option<&'static SharedDriver>,
+    rng: RngWrapper,
 }
 
 struct Html<const LEN: usize> (String<LEN>);
@@ -141,6 +387,23 @@ impl<const LEN: usize> Content for Html<LEN> {
     }
 }
 
+/// Hand written JSON for things polling the controller
+struct Json<const LEN: usize> (String<LEN>);
+
+impl<const LEN: usize> Content for Json<LEN> {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: EioWrite>(self, writer: W) -> Result<(), W::Error> {
+        self.0.as_bytes().write_content(writer).await
+    }
+}
+
 impl AppProps {
     async fn index() -> impl IntoResponse {
         let build_date = chrono::Utc.timestamp_millis_opt(BUILD_DATE).single().expect("Invalid build date in binary");
@@ -157,12 +420,491 @@ impl AppProps {
             set = false;
             OffsetDateTime::UNIX_EPOCH
         });
-        let sunset = calculate_sunset(&time, coordinates);
 
-        let mut buf = String::<150>::new();
-        let _ = write!(&mut buf, "<p>Configured: {configured:?}</p><p>Time set: {set:?}</p><p>Time: {time:?}</p><p>Sunset: {sunset:?}</p>");
+        let mut buf = String::<1024>::new();
+        let _ = write!(&mut buf, "<p>Configured: {configured:?}</p><p>Time set: {set:?}</p><p>Time: {time:?}</p>");
+        for (event, at) in solar::events(coordinates, time.date(), time.offset()) {
+            let _ = match at {
+                Ok(at) => write!(&mut buf, "<p>{event:?}: {at}</p>"),
+                Err(no_event) => write!(&mut buf, "<p>{event:?}: none today ({no_event:?})</p>"),
+            };
+        }
         Html(buf)
     }
+    async fn driver(driver: Option<&'static SharedDriver>) -> impl IntoResponse {
+        let mut buf = String::<512>::new();
+        match driver {
+            None => {
+                let _ = write!(&mut buf, "<p>No driver UART configured</p>");
+            },
+            Some(driver) => {
+                let mut tmc = driver.lock().await;
+                let _ = match with_timeout(TMC_TIMEOUT, tmc.drv_status()).await {
+                    Ok(Ok(status)) => write!(&mut buf, "<p>Fault: {:?}</p><p>{status:?}</p>", status.fault()),
+                    Ok(Err(e)) => write!(&mut buf, "<p>Failed to read DRV_STATUS: {e:?}</p>"),
+                    Err(_) => write!(&mut buf, "<p>Timeout reading DRV_STATUS</p>"),
+                };
+                let _ = match with_timeout(TMC_TIMEOUT, tmc.stall_guard_result()).await {
+                    Ok(Ok(result)) => write!(&mut buf, "<p>SG_RESULT: {result}</p>"),
+                    Ok(Err(e)) => write!(&mut buf, "<p>Failed to read SG_RESULT: {e:?}</p>"),
+                    Err(_) => write!(&mut buf, "<p>Timeout reading SG_RESULT</p>"),
+                };
+            },
+        }
+        Html(buf)
+    }
+    fn write_status<const LEN: usize>(buf: &mut String<LEN>, id: usize) {
+        let MotorStatus { position, height, moving, calibration, fault } = motor_status(id);
+        let _ = write!(
+            buf,
+            "<h3>Blind {id}</h3><p>Position: {position}/{height} ({}%)</p><p>Moving: {moving:?}</p><p>Calibrating: {calibration:?}</p><p>Fault: {fault:?}</p>",
+            position.clamp(0, height as i32) as usize * 100 / height.max(1),
+        );
+        let _ = match hold(id) {
+            None => write!(buf, "<p>Schedule held: no</p>"),
+            Some(Hold { until: None, .. }) => write!(buf, "<p>Schedule held: until it next changes</p>"),
+            Some(Hold { until: Some(until), .. }) => {
+                let offset = (SystemTime {}).datetime().map_or(UtcOffset::UTC, |time| time.offset());
+                let until = OffsetDateTime::from_unix_timestamp(until).unwrap_or(OffsetDateTime::UNIX_EPOCH).to_offset(offset);
+                write!(buf, "<p>Schedule held: until {until}</p>")
+            },
+        };
+    }
+    async fn status() -> impl IntoResponse {
+        let mut buf = String::<{ 400 * BLIND_COUNT }>::new();
+        for id in 0..BLIND_COUNT {
+            Self::write_status(&mut buf, id);
+        }
+        Html(buf)
+    }
+    async fn blind_status(id: usize) -> impl IntoResponse {
+        let mut buf = String::<400>::new();
+        if id < BLIND_COUNT {
+            Self::write_status(&mut buf, id);
+        } else {
+            let _ = write!(&mut buf, "<p>No blind {id}</p>");
+        }
+        Html(buf)
+    }
+    async fn rules(id: usize) -> impl IntoResponse {
+        // Each rule is wrapped in `<li></li>`, with room left for the heading and the list tags
+        let mut buf = String::<{ 64 + schedule::MAX_RULES * (schedule::MAX_RULE_TEXT_LEN + 9) }>::new();
+        match RULES.get(id) {
+            Some(rules) => {
+                let _ = write!(&mut buf, "<h3>Blind {id} schedule</h3><ol start='0'>");
+                let scenes = SCENES.lock().await;
+                for rule in rules.lock().await.iter() {
+                    let _ = write!(&mut buf, "<li>{}</li>", rule.display(&scenes));
+                }
+                let _ = write!(&mut buf, "</ol>");
+            },
+            None => {
+                let _ = write!(&mut buf, "<p>No blind {id}</p>");
+            },
+        }
+        Html(buf)
+    }
+    /// Parses the text form of a rule, which can refer to scenes by name, and adds it to blind `id`
+    async fn add_rule(flash: &'static SharedFlash, id: usize, text: &str) -> String<64> {
+        // Held until the rule is added so the scene it moves to can't be deleted in between
+        let scenes = SCENES.lock().await;
+        match Rule::parse(text, &scenes) {
+            Ok(rule) => Self::edit_rules(flash, id, move |rules| rules.push(rule).map_err(|_| "Too many rules")).await,
+            Err(e) => {
+                let mut buf = String::<64>::new();
+                let _ = write!(&mut buf, "Invalid rule: {e:?}");
+                buf
+            },
+        }
+    }
+    /// Applies `edit` to the rules of blind `id` and saves them
+    async fn edit_rules(
+        flash: &'static SharedFlash,
+        id: usize,
+        edit: impl FnOnce(&mut Rules) -> Result<(), &'static str>,
+    ) -> String<64> {
+        let mut buf = String::<64>::new();
+        let Some(rules) = RULES.get(id) else {
+            let _ = write!(&mut buf, "No blind {id}, expected 0-{}", BLIND_COUNT - 1);
+            return buf;
+        };
+
+        let mut rules = rules.lock().await;
+        let mut edited = rules.clone();
+        if let Err(e) = edit(&mut edited) {
+            let _ = write!(&mut buf, "{e}");
+            return buf;
+        }
+
+        match save_rules(flash, id, &edited).await {
+            Ok(()) => {
+                *rules = edited;
+                RULES_CHANGED[id].signal(());
+                let _ = write!(&mut buf, "Blind {id} has {} rules", rules.len());
+            },
+            Err(e) => {
+                error!("Failed to save schedule {id} to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    async fn glare(id: usize, coordinates: Coordinates) -> impl IntoResponse {
+        let mut buf = String::<256>::new();
+        let Some(glare) = GLARE.get(id) else {
+            let _ = write!(&mut buf, "<p>No blind {id}</p>");
+            return Html(buf);
+        };
+
+        let _ = match *glare.lock().await {
+            Some(glare) => write!(&mut buf, "<h3>Blind {id} glare control</h3><p>{glare}</p>"),
+            None => write!(&mut buf, "<h3>Blind {id} glare control</h3><p>Off</p>"),
+        };
+        if let Ok(time) = (SystemTime {}).datetime() {
+            let sun = solar::sun_position(coordinates, time);
+            let _ = write!(&mut buf, "<p>Sun azimuth: {:.1}, elevation: {:.1}</p>", sun.azimuth, sun.elevation);
+        }
+        Html(buf)
+    }
+    /// Sets or turns off glare control of blind `id` and saves it
+    async fn set_glare(flash: &'static SharedFlash, id: usize, setting: Option<Glare>) -> String<64> {
+        let mut buf = String::<64>::new();
+        let Some(glare) = GLARE.get(id) else {
+            let _ = write!(&mut buf, "No blind {id}, expected 0-{}", BLIND_COUNT - 1);
+            return buf;
+        };
+
+        let mut glare = glare.lock().await;
+        match save_glare(flash, id, setting).await {
+            Ok(()) => {
+                *glare = setting;
+                RULES_CHANGED[id].signal(());
+                let _ = match setting {
+                    Some(setting) => write!(&mut buf, "Blind {id} glare control: {setting}"),
+                    None => write!(&mut buf, "Blind {id} glare control off"),
+                };
+            },
+            Err(e) => {
+                error!("Failed to save glare setting {id} to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    async fn time_zone() -> impl IntoResponse {
+        let mut buf = String::<{ 128 + 2 * tz::MAX_TZ_LEN }>::new();
+        let now = ((SystemTime {}).get_time_us() / 1_000_000) as i64;
+        let _ = match &*TIME_ZONE.lock().await {
+            Some(zone) => write!(&mut buf, "<p>Time zone: {zone}</p><p>Rules: {}</p><p>Currently: {}</p>", zone.posix(), zone.name_at(now)),
+            None => write!(&mut buf, "<p>Time zone: not loaded</p>"),
+        };
+        Html(buf)
+    }
+    /// Parses an IANA zone name or a POSIX TZ string, with `/` escaped as `%2F`, and makes it the time
+    /// zone of all the blinds
+    async fn set_time_zone(flash: &'static SharedFlash, text: &str) -> String<{ 64 + tz::MAX_TZ_LEN }> {
+        let mut buf = String::new();
+        let Some(text) = url_decode::<{ tz::MAX_TZ_LEN }>(text) else {
+            let _ = write!(&mut buf, "Invalid time zone: bad escape or too long");
+            return buf;
+        };
+        let zone = match text.parse::<tz::TimeZone>() {
+            Ok(zone) => zone,
+            Err(e) => {
+                let _ = write!(&mut buf, "Invalid time zone: {e:?}");
+                return buf;
+            },
+        };
+
+        let mut time_zone = TIME_ZONE.lock().await;
+        match save_time_zone(flash, &zone).await {
+            Ok(()) => {
+                let _ = write!(&mut buf, "Time zone: {zone}");
+                *time_zone = Some(zone);
+                TIME_ZONE_CHANGED.signal(());
+            },
+            Err(e) => {
+                error!("Failed to save time zone to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    async fn vacation() -> impl IntoResponse {
+        let mut buf = String::<64>::new();
+        let _ = match *VACATION.lock().await {
+            Some(vacation) => write!(&mut buf, "<p>Vacation mode: up to {} minutes either way</p>", vacation.max_jitter),
+            None => write!(&mut buf, "<p>Vacation mode: off</p>"),
+        };
+        Html(buf)
+    }
+    /// Turns vacation mode on or off for all the blinds and saves it
+    async fn set_vacation(flash: &'static SharedFlash, setting: Option<Vacation>) -> String<64> {
+        let mut buf = String::<64>::new();
+        if setting.is_some_and(|vacation| vacation.max_jitter > schedule::MAX_JITTER) {
+            let _ = write!(&mut buf, "Expected at most {} minutes", schedule::MAX_JITTER);
+            return buf;
+        }
+
+        let mut vacation = VACATION.lock().await;
+        match save_vacation(flash, setting).await {
+            Ok(()) => {
+                *vacation = setting;
+                for changed in &RULES_CHANGED {
+                    changed.signal(());
+                }
+                let _ = match setting {
+                    Some(setting) => write!(&mut buf, "Vacation mode on, up to {} minutes", setting.max_jitter),
+                    None => write!(&mut buf, "Vacation mode off"),
+                };
+            },
+            Err(e) => {
+                error!("Failed to save vacation setting to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    /// The next `count` rules of blind `id` to fire as a JSON array of
+    /// `{"date":"2024-06-21","time":"19:42","trigger":"sunset","position":100}`, with the name of the
+    /// scene as `"scene"` for rules that move to a scene and the minutes of a gentle move as `"over"`
+    async fn upcoming(id: usize, count: usize, coordinates: Coordinates) -> impl IntoResponse {
+        let mut buf = String::<{ 128 * MAX_UPCOMING + 2 }>::new();
+        let Some(rules) = RULES.get(id) else {
+            let _ = write!(&mut buf, "{{\"error\":\"No blind {id}\"}}");
+            return Json(buf);
+        };
+        let Ok(now) = (SystemTime {}).datetime() else {
+            let _ = write!(&mut buf, "{{\"error\":\"Time not set\"}}");
+            return Json(buf);
+        };
+
+        // Today followed by the next days
+        let mut dates = [now.date(); UPCOMING_DAYS];
+        for i in 1..UPCOMING_DAYS {
+            dates[i] = dates[i - 1].next_day().unwrap_or(dates[i - 1]);
+        }
+        let zone = TIME_ZONE.lock().await;
+        let days = dates.map(|date| solar_day(date, offset_on(zone.as_ref(), date, now.offset()), coordinates));
+        drop(zone);
+
+        let minute = now.hour() as u16 * 60 + now.minute() as u16;
+        let vacation = VACATION.lock().await.map(|vacation| vacation.for_blind(id));
+        let scenes = SCENES.lock().await;
+        let positions = scene::positions_of(&scenes, id);
+        let events: Vec<Upcoming, MAX_UPCOMING> =
+            schedule::upcoming(&rules.lock().await, &days, minute, vacation.as_ref(), &positions);
+
+        let _ = write!(&mut buf, "[");
+        for (i, Upcoming { day, minute, trigger, target, position, ramp }) in events.into_iter().take(count).enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(
+                &mut buf,
+                "{separator}{{\"date\":\"{}\",\"time\":\"{:02}:{:02}\",\"trigger\":\"{trigger}\",\"position\":{position}",
+                dates[day],
+                minute / 60,
+                minute % 60,
+            );
+            if let Target::Scene(slot) = target {
+                if let Some(scene) = &scenes[slot as usize] {
+                    let _ = write!(&mut buf, ",\"scene\":\"{}\"", scene.name);
+                }
+            }
+            if ramp > 0 {
+                let _ = write!(&mut buf, ",\"over\":{ramp}");
+            }
+            let _ = write!(&mut buf, "}}");
+        }
+        let _ = write!(&mut buf, "]");
+        Json(buf)
+    }
+    async fn scenes() -> impl IntoResponse {
+        // Each scene is a link to it wrapped in `<li></li>`, with room left for the heading and the list tags
+        const ENTRY_LEN: usize = "<li><a href='/scene/'></a></li>".len() + scene::MAX_NAME_LEN + scene::MAX_SCENE_TEXT_LEN;
+        let mut buf = String::<{ 32 + scene::MAX_SCENES * ENTRY_LEN }>::new();
+        let _ = write!(&mut buf, "<h3>Scenes</h3><ul>");
+        for scene in SCENES.lock().await.iter().flatten() {
+            let _ = write!(&mut buf, "<li><a href='/scene/{}'>{scene}</a></li>", scene.name);
+        }
+        let _ = write!(&mut buf, "</ul>");
+        Html(buf)
+    }
+    /// Moves every blind the scene called `name` has a position for
+    async fn scene(senders: Senders, name: &str) -> String<64> {
+        let mut buf = String::<64>::new();
+        let scenes = SCENES.lock().await;
+        let Some(scene) = scene::find(&scenes, name).and_then(|slot| scenes[slot].as_ref()) else {
+            let _ = write!(&mut buf, "No scene {name}");
+            return buf;
+        };
+        let positions = scene.positions;
+        drop(scenes);
+
+        for (id, (&sender, position)) in senders.iter().zip(positions).enumerate() {
+            if let Some(position) = position {
+                send_manual(sender, id, StepCommand::MoveTo(position)).await;
+            }
+        }
+        let _ = write!(&mut buf, "Scene {name}");
+        buf
+    }
+    /// Adds or replaces a scene and saves the scenes
+    async fn set_scene(flash: &'static SharedFlash, scene: Scene) -> String<64> {
+        let mut buf = String::<64>::new();
+        let mut scenes = SCENES.lock().await;
+        let mut edited = scenes.clone();
+        let name = scene.name.clone();
+        if scene::set(&mut edited, scene).is_err() {
+            let _ = write!(&mut buf, "Too many scenes");
+            return buf;
+        }
+
+        match save_scenes(flash, &edited).await {
+            Ok(()) => {
+                *scenes = edited;
+                let _ = write!(&mut buf, "Scene {name} saved");
+            },
+            Err(e) => {
+                error!("Failed to save scenes to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    /// Deletes the scene called `name` unless a rule of any blind moves to it
+    async fn delete_scene(flash: &'static SharedFlash, name: &str) -> String<64> {
+        let mut buf = String::<64>::new();
+        let mut scenes = SCENES.lock().await;
+        let Some(slot) = scene::find(&scenes, name) else {
+            let _ = write!(&mut buf, "No scene {name}");
+            return buf;
+        };
+
+        for (id, rules) in RULES.iter().enumerate() {
+            if rules.lock().await.iter().any(|rule| rule.target == Target::Scene(slot as u8)) {
+                let _ = write!(&mut buf, "Scene {name} is used by a rule of blind {id}");
+                return buf;
+            }
+        }
+
+        let mut edited = scenes.clone();
+        edited[slot] = None;
+        match save_scenes(flash, &edited).await {
+            Ok(()) => {
+                *scenes = edited;
+                let _ = write!(&mut buf, "Scene {name} deleted");
+            },
+            Err(e) => {
+                error!("Failed to save scenes to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    async fn timers() -> impl IntoResponse {
+        let mut buf = String::<512>::new();
+        let offset = (SystemTime {}).datetime().map_or(UtcOffset::UTC, |time| time.offset());
+        let _ = write!(&mut buf, "<h3>Timers</h3><ol start='0'>");
+        for OneShot { due, blind, position } in TIMERS.lock().await.iter() {
+            let due = OffsetDateTime::from_unix_timestamp(*due).unwrap_or(OffsetDateTime::UNIX_EPOCH).to_offset(offset);
+            let _ = write!(&mut buf, "<li>Blind {blind} to {position}% at {due}</li>");
+        }
+        let _ = write!(&mut buf, "</ol>");
+        Html(buf)
+    }
+    /// Applies `edit` to the timers and saves them
+    async fn edit_timers(
+        flash: &'static SharedFlash,
+        edit: impl FnOnce(&mut Timers) -> Result<(), &'static str>,
+    ) -> String<64> {
+        let mut buf = String::<64>::new();
+        let mut timers = TIMERS.lock().await;
+        let mut edited = timers.clone();
+        if let Err(e) = edit(&mut edited) {
+            let _ = write!(&mut buf, "{e}");
+            return buf;
+        }
+
+        match save_timers(flash, &edited).await {
+            Ok(()) => {
+                *timers = edited;
+                TIMERS_CHANGED.signal(());
+                let _ = write!(&mut buf, "{} timers pending", timers.len());
+            },
+            Err(e) => {
+                error!("Failed to save timers to NVS: {e:?}");
+                let _ = write!(&mut buf, "Failed to save: {e:?}");
+            },
+        }
+        buf
+    }
+    async fn add_timer(flash: &'static SharedFlash, id: usize, request: TimerRequest) -> String<64> {
+        let mut buf = String::<64>::new();
+        if id >= BLIND_COUNT {
+            let _ = write!(&mut buf, "No blind {id}, expected 0-{}", BLIND_COUNT - 1);
+            return buf;
+        }
+
+        let Ok(now) = (SystemTime {}).datetime() else {
+            let _ = write!(&mut buf, "Time not set");
+            return buf;
+        };
+        let zone = TIME_ZONE.lock().await;
+        let offset_at = |time| zone.as_ref().map_or(now.offset().whole_seconds(), |zone| zone.offset_at(time));
+        let due = request.when.resolve(now.unix_timestamp(), offset_at);
+        drop(zone);
+        let timer = OneShot { due, blind: id as u8, position: request.position };
+
+        Self::edit_timers(flash, move |timers| one_shot::add(timers, timer).map_err(|_| "Too many timers")).await
+    }
+    /// Ends the schedule hold of blind `id`, moving it to where the schedule wants it
+    async fn resume(id: usize) -> String<64> {
+        let mut buf = String::<64>::new();
+        if id >= BLIND_COUNT {
+            let _ = write!(&mut buf, "No blind {id}, expected 0-{}", BLIND_COUNT - 1);
+            return buf;
+        }
+
+        // Expired rather than cleared so `schedule_task` reasserts the schedule
+        let was_held = HOLD[id].lock(|hold| match hold.get() {
+            Some(held) => {
+                hold.set(Some(Hold { until: Some(i64::MIN), ..held }));
+                true
+            },
+            None => false,
+        });
+        if was_held {
+            RULES_CHANGED[id].signal(());
+            let _ = write!(&mut buf, "Blind {id} schedule resumed");
+        } else {
+            let _ = write!(&mut buf, "Blind {id} schedule wasn't held");
+        }
+        buf
+    }
+    /// Queues `command` for blind `id`
+    async fn command(senders: Senders, id: usize, command: StepCommand) -> String<64> {
+        let mut buf = String::<64>::new();
+        match senders.get(id) {
+            Some(&sender) => {
+                send_manual(sender, id, command).await;
+                let _ = write!(&mut buf, "Blind {id}: {command:?}");
+            },
+            None => {
+                let _ = write!(&mut buf, "No blind {id}, expected 0-{}", BLIND_COUNT - 1);
+            },
+        }
+        buf
+    }
+
+    async fn move_to(senders: Senders, id: usize, pct: u8) -> String<64> {
+        if pct > 100 {
+            let mut buf = String::<64>::new();
+            let _ = write!(&mut buf, "Invalid position {pct}%, expected 0-100");
+            buf
+        } else {
+            Self::command(senders, id, StepCommand::MoveTo(pct)).await
+        }
+    }
     // TODO: have the handler finish and get an embassy task to actually reboot or something
     #[allow(dependency_on_unit_never_type_fallback)]
     async fn reboot() -> impl IntoResponse {
@@ -231,102 +973,539 @@ impl AppBuilder for AppProps {
     type PathRouter = impl picoserve::routing::PathRouter;
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter> {
-        let Self { sender, coordinates } = self;
+        let Self { senders, flash, coordinates, driver, rng } = self;
         picoserve::Router::new()
             .route("/", get(|| Self::index()))
             .route("/time", get(move || Self::time(coordinates)))
             .route("/reboot", get(|| Self::reboot()))
+            .route("/status", get(|| Self::status()))
+            .route("/driver", get(move || Self::driver(driver)))
             .route(
-                ("/forward", parse_path_segment::<usize>()),
-                get(move |n| async move {
-                    sender.send(StepCommand::Forward(n)).await;
-                    let mut buf = String::<64>::new();
-                    let _ = write!(&mut buf, "Forward {n}");
-                    buf
+                "/stop",
+                get(move || async move {
+                    for (id, sender) in senders.into_iter().enumerate() {
+                        send_manual(sender, id, StepCommand::Stop).await;
+                    }
+                    "Stop all"
                 }),
             )
+            // Routes from before there were several blinds, acting on the first
+            .route(("/forward", parse_path_segment::<usize>()), get(move |n| Self::command(senders, 0, StepCommand::Forward(n))))
+            .route(("/backward", parse_path_segment::<usize>()), get(move |n| Self::command(senders, 0, StepCommand::Backward(n))))
+            .route("/raise", get(move || Self::command(senders, 0, StepCommand::Raise)))
+            .route("/lower", get(move || Self::command(senders, 0, StepCommand::Lower)))
+            .route(("/position", parse_path_segment::<u8>()), get(move |pct| Self::move_to(senders, 0, pct)))
+            .route(("/steps", parse_path_segment::<usize>()), get(move |n| Self::command(senders, 0, StepCommand::MoveToSteps(n))))
+            .route("/calibrate", get(move || Self::command(senders, 0, StepCommand::Calibrate)))
+            .route("/calibrate/mark", get(move || Self::command(senders, 0, StepCommand::Mark)))
+            .route("/calibrate/cancel", get(move || Self::command(senders, 0, StepCommand::CancelCalibration)))
+            .route("/home", get(move || Self::command(senders, 0, StepCommand::Home)))
+            .route("/clear", get(move || Self::command(senders, 0, StepCommand::ClearFault)))
+            .route(("/blind", parse_path_segment::<usize>(), "/status"), get(|id| Self::blind_status(id)))
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/forward", parse_path_segment::<usize>()),
+                get(move |(id, n)| Self::command(senders, id, StepCommand::Forward(n))),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/backward", parse_path_segment::<usize>()),
+                get(move |(id, n)| Self::command(senders, id, StepCommand::Backward(n))),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/position", parse_path_segment::<u8>()),
+                get(move |(id, pct)| Self::move_to(senders, id, pct)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/steps", parse_path_segment::<usize>()),
+                get(move |(id, n)| Self::command(senders, id, StepCommand::MoveToSteps(n))),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/calibrate"),
+                get(move |id| Self::command(senders, id, StepCommand::Calibrate)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/calibrate/mark"),
+                get(move |id| Self::command(senders, id, StepCommand::Mark)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/calibrate/cancel"),
+                get(move |id| Self::command(senders, id, StepCommand::CancelCalibration)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/home"),
+                get(move |id| Self::command(senders, id, StepCommand::Home)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/clear"),
+                get(move |id| Self::command(senders, id, StepCommand::ClearFault)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/stop"),
+                get(move |id| Self::command(senders, id, StepCommand::Stop)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/raise"),
+                get(move |id| Self::command(senders, id, StepCommand::Raise)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/lower"),
+                get(move |id| Self::command(senders, id, StepCommand::Lower)),
+            )
+            .route(("/blind", parse_path_segment::<usize>(), "/resume"), get(|id| Self::resume(id)))
+            .route(("/blind", parse_path_segment::<usize>(), "/rules"), get(|id| Self::rules(id)))
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/rules/add", parse_path_segment::<String<RULE_TEXT_LEN>>()),
+                get(move |(id, text): (usize, String<RULE_TEXT_LEN>)| async move { Self::add_rule(flash, id, &text).await }),
+            )
             .route(
-                ("/backward", parse_path_segment::<usize>()),
-                get(move |n| async move {
-                    sender.send(StepCommand::Backward(n)).await;
-                    let mut buf = String::<64>::new();
-                    let _ = write!(&mut buf, "Backwards {n}");
-                    buf
+                ("/blind", parse_path_segment::<usize>(), "/rules/delete", parse_path_segment::<usize>()),
+                get(move |(id, index)| {
+                    Self::edit_rules(flash, id, move |rules| {
+                        if index < rules.len() {
+                            rules.remove(index);
+                            Ok(())
+                        } else {
+                            Err("No such rule")
+                        }
+                    })
                 }),
             )
             .route(
-                "/raise",
-                get(move || async move {
-                    sender.send(StepCommand::Raise).await;
-                    "Raise"
+                ("/blind", parse_path_segment::<usize>(), "/rules/clear"),
+                get(move |id| {
+                    Self::edit_rules(flash, id, |rules| {
+                        rules.clear();
+                        Ok(())
+                    })
                 }),
             )
             .route(
-                "/lower",
-                get(move || async move {
-                    sender.send(StepCommand::Lower).await;
-                    "Lower"
+                ("/blind", parse_path_segment::<usize>(), "/rules/reset"),
+                get(move |id| {
+                    Self::edit_rules(flash, id, |rules| {
+                        *rules = default_rules();
+                        Ok(())
+                    })
                 }),
             )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/upcoming"),
+                get(move |id| Self::upcoming(id, DEFAULT_UPCOMING, coordinates)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/upcoming", parse_path_segment::<usize>()),
+                get(move |(id, count)| Self::upcoming(id, count, coordinates)),
+            )
+            .route("/scenes", get(|| Self::scenes()))
+            .route(
+                ("/scenes/set", parse_path_segment::<Scene>()),
+                get(move |scene| Self::set_scene(flash, scene)),
+            )
+            .route(
+                ("/scenes/delete", parse_path_segment::<scene::Name>()),
+                get(move |name: scene::Name| async move { Self::delete_scene(flash, &name).await }),
+            )
+            .route(
+                ("/scene", parse_path_segment::<scene::Name>()),
+                get(move |name: scene::Name| async move { Self::scene(senders, &name).await }),
+            )
+            .route("/timers", get(|| Self::timers()))
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/timers/add", parse_path_segment::<TimerRequest>()),
+                get(move |(id, request)| Self::add_timer(flash, id, request)),
+            )
+            .route(
+                ("/timers/cancel", parse_path_segment::<usize>()),
+                get(move |index| {
+                    Self::edit_timers(flash, move |timers| {
+                        if index < timers.len() {
+                            timers.remove(index);
+                            Ok(())
+                        } else {
+                            Err("No such timer")
+                        }
+                    })
+                }),
+            )
+            .route("/tz", get(|| Self::time_zone()))
+            .route(
+                ("/tz/set", parse_path_segment::<String<TIME_ZONE_TEXT_LEN>>()),
+                get(move |text: String<TIME_ZONE_TEXT_LEN>| async move { Self::set_time_zone(flash, &text).await }),
+            )
+            .route("/vacation", get(|| Self::vacation()))
+            .route(
+                ("/vacation/on", parse_path_segment::<u16>()),
+                get(move |max_jitter| {
+                    let seed = rng.clone().next_u32();
+                    Self::set_vacation(flash, Some(Vacation { seed, max_jitter }))
+                }),
+            )
+            .route("/vacation/off", get(move || Self::set_vacation(flash, None)))
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/glare"),
+                get(move |id| Self::glare(id, coordinates)),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/glare/set", parse_path_segment::<Glare>()),
+                get(move |(id, glare)| Self::set_glare(flash, id, Some(glare))),
+            )
+            .route(
+                ("/blind", parse_path_segment::<usize>(), "/glare/off"),
+                get(move |id| Self::set_glare(flash, id, None)),
+            )
             .layer(NoStoreLayer)
     }
 }
 
-fn calculate_sunset(datetime: &OffsetDateTime, coordinates: Coordinates) -> Result<OffsetDateTime, Error> {
-    let date = NaiveDate::from_ymd_opt(datetime.year(), datetime.month() as u32, datetime.day() as u32)
-        .ok_or(Error::Other("Failed to convert time::Date into chrono::NaiveDate"))?;
-    let day = SolarDay::new(coordinates, date);
-    let sunset = day.event_time(SolarEvent::Sunset);
-    let offset_sunset = datetime
-        .replace_hour(sunset.hour() as u8)?
-        .replace_minute(sunset.minute() as u8)?
-        .replace_second(sunset.second() as u8)?;
+/// Schedule rules of each blind, edited over HTTP and saved to NVS
+static RULES: [Mutex<CriticalSectionRawMutex, Rules>; BLIND_COUNT] = [const { Mutex::new(Rules::new()) }; BLIND_COUNT];
+/// Scenes shared by all the blinds, saved to NVS. Lock before `RULES` when both are needed
+static SCENES: Mutex<CriticalSectionRawMutex, Scenes> = Mutex::new([const { None }; scene::MAX_SCENES]);
+/// Longest text form of a rule accepted over HTTP
+const RULE_TEXT_LEN: usize = 80;
+
+/// Glare control of each blind, `None` when it's off
+static GLARE: [Mutex<CriticalSectionRawMutex, Option<Glare>>; BLIND_COUNT] = [const { Mutex::new(None) }; BLIND_COUNT];
+/// Vacation mode of all the blinds, `None` when it's off
+static VACATION: Mutex<CriticalSectionRawMutex, Option<Vacation>> = Mutex::new(None);
+/// Time zone the schedules and status pages use, saved to NVS. `None` until it's loaded at startup
+static TIME_ZONE: Mutex<CriticalSectionRawMutex, Option<tz::TimeZone>> = Mutex::new(None);
+/// Signalled when the time zone is changed or NTP steps the clock so `time_zone_task` updates the offset
+static TIME_ZONE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Time zone used until one is set over HTTP, as an IANA name or a POSIX TZ string. Set with the `TZ`
+/// env variable, the default is the UK
+const DEFAULT_TIME_ZONE: &str = match option_env!("TZ") {
+    None => "Europe/London",
+    Some(v) => v,
+};
+/// Longest time zone accepted over HTTP, with room for `/` to be escaped as `%2F`
+const TIME_ZONE_TEXT_LEN: usize = 128;
+
+/// Signalled when a blind's rules, glare control or vacation mode are edited so `schedule_task`
+/// re-evaluates them straight away
+static RULES_CHANGED: [Signal<CriticalSectionRawMutex, ()>; BLIND_COUNT] = [const { Signal::new() }; BLIND_COUNT];
+
+/// At startup a blind is only moved to catch up with the rule that should be in force if it fired
+/// within this many minutes, older events are left alone in case the blind was moved by hand since
+const SCHEDULE_GRACE_MINUTES: u32 = match option_env!("SCHEDULE_GRACE_MINUTES") {
+    None => 240,
+    Some(v) => match u32::from_str_radix(v, 10) {
+        Ok(v) => v,
+        Err(_) => panic!("SCHEDULE_GRACE_MINUTES env variable failed to parse as u32"),
+    },
+};
 
-    debug!("Sunset is at {offset_sunset}");
+/// Most events `/blind/<id>/upcoming` returns, and how many when it isn't given a count
+const MAX_UPCOMING: usize = 16;
+const DEFAULT_UPCOMING: usize = 5;
+/// Days `/blind/<id>/upcoming` looks ahead through, enough for rules that only fire once a week
+const UPCOMING_DAYS: usize = 8;
+
+/// Days of rules `schedule_task` looks back through to find the one that fired last
+const SCHEDULE_LOOKBACK_DAYS: usize = 8;
+
+/// Raise at 12:30 and lower at sunset, every day
+fn default_rules() -> Rules {
+    let mut rules = Rules::new();
+    let rule = |trigger, position| Rule {
+        weekdays: schedule::ALL_DAYS,
+        trigger,
+        earliest: None,
+        latest: None,
+        target: Target::Position(position),
+        ramp: 0,
+    };
+    let _ = rules.push(rule(Trigger::Time(12 * 60 + 30), 0));
+    let _ = rules.push(rule(Trigger::Sunset(0), 100));
+    rules
+}
 
-    Ok(offset_sunset)
+async fn load_rules(flash: &SharedFlash, id: usize) -> Option<Rules> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; schedule::RULES_LEN];
+    match nvs.read_record(nvs::schedule_offset(id), &mut buf) {
+        Ok(true) => Some(schedule::decode_rules(&buf)),
+        Ok(false) => None,
+        Err(e) => {
+            error!("Failed to read schedule {id} from NVS: {e:?}");
+            None
+        },
+    }
 }
 
-#[embassy_executor::task]
-async fn schedule_task(sender: Sender<'static, CriticalSectionRawMutex, StepCommand, 10>, coordinates: Coordinates) -> ! {
+async fn save_rules(flash: &SharedFlash, id: usize, rules: &[Rule]) -> Result<(), nvs::Error> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    nvs.write_record(nvs::schedule_offset(id), &schedule::encode_rules(rules))
+}
+
+async fn load_scenes(flash: &SharedFlash) -> Scenes {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; scene::SCENES_LEN];
+    match nvs.read_record(nvs::SCENES_OFFSET, &mut buf) {
+        Ok(true) => scene::decode_scenes(&buf),
+        Ok(false) => [const { None }; scene::MAX_SCENES],
+        Err(e) => {
+            error!("Failed to read scenes from NVS: {e:?}");
+            [const { None }; scene::MAX_SCENES]
+        },
+    }
+}
+
+/// Saves the scenes and has every blind's schedule re-evaluated, as rules may move to them
+async fn save_scenes(flash: &SharedFlash, scenes: &Scenes) -> Result<(), nvs::Error> {
+    let r = {
+        let mut flash = flash.lock().await;
+        let mut nvs = Nvs::new(&mut flash);
+        nvs.write_record(nvs::SCENES_OFFSET, &scene::encode_scenes(scenes))
+    };
+
+    match &r {
+        Ok(()) => {
+            for changed in &RULES_CHANGED {
+                changed.signal(());
+            }
+        },
+        Err(e) => error!("Failed to save scenes to NVS: {e:?}"),
+    }
+    r
+}
+
+async fn load_glare(flash: &SharedFlash, id: usize) -> Option<Glare> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; schedule::GLARE_LEN];
+    match nvs.read_record(nvs::glare_offset(id), &mut buf) {
+        Ok(true) => Glare::decode(&buf),
+        Ok(false) => None,
+        Err(e) => {
+            error!("Failed to read glare setting {id} from NVS: {e:?}");
+            None
+        },
+    }
+}
+
+/// Glare control being off is saved as an invalid setting
+async fn save_glare(flash: &SharedFlash, id: usize, glare: Option<Glare>) -> Result<(), nvs::Error> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let buf = glare.map_or([0xff; schedule::GLARE_LEN], |glare| glare.encode());
+    nvs.write_record(nvs::glare_offset(id), &buf)
+}
+
+async fn load_vacation(flash: &SharedFlash) -> Option<Vacation> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; schedule::VACATION_LEN];
+    match nvs.read_record(nvs::VACATION_OFFSET, &mut buf) {
+        Ok(true) => Vacation::decode(&buf),
+        Ok(false) => None,
+        Err(e) => {
+            error!("Failed to read vacation setting from NVS: {e:?}");
+            None
+        },
+    }
+}
+
+/// Vacation mode being off is saved as an invalid setting
+async fn save_vacation(flash: &SharedFlash, vacation: Option<Vacation>) -> Result<(), nvs::Error> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let buf = vacation.map_or([0xff; schedule::VACATION_LEN], |vacation| vacation.encode());
+    nvs.write_record(nvs::VACATION_OFFSET, &buf)
+}
+
+/// The saved time zone, or `DEFAULT_TIME_ZONE` if there isn't one
+async fn load_time_zone(flash: &SharedFlash) -> tz::TimeZone {
+    let saved = {
+        let mut flash = flash.lock().await;
+        let mut nvs = Nvs::new(&mut flash);
+
+        let mut buf = [0; tz::TZ_LEN];
+        match nvs.read_record(nvs::TIME_ZONE_OFFSET, &mut buf) {
+            Ok(true) => tz::TimeZone::decode(&buf),
+            Ok(false) => None,
+            Err(e) => {
+                error!("Failed to read time zone from NVS: {e:?}");
+                None
+            },
+        }
+    };
+
+    saved.unwrap_or_else(|| DEFAULT_TIME_ZONE.parse().unwrap_or_else(|e| {
+        error!("TZ env variable failed to parse ({e:?}), using UTC");
+        tz::TimeZone { iana: None, name: "UTC".try_into().unwrap_or_default(), offset: 0, dst: None }
+    }))
+}
+
+async fn save_time_zone(flash: &SharedFlash, zone: &tz::TimeZone) -> Result<(), nvs::Error> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    nvs.write_record(nvs::TIME_ZONE_OFFSET, &zone.encode())
+}
+
+/// Decodes `%XX` escapes, so characters such as `/` can be part of a path segment
+fn url_decode<const N: usize>(s: &str) -> Option<String<N>> {
+    let mut decoded = Vec::<u8, N>::new();
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            },
+            byte => byte,
+        };
+        decoded.push(byte).ok()?;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// UTC offset of `zone` at local noon on `date`, which is `offset` unless there's a daylight
+/// saving change in between. `offset` itself without a time zone
+fn offset_on(zone: Option<&tz::TimeZone>, date: Date, offset: UtcOffset) -> UtcOffset {
+    let Some(zone) = zone else {
+        return offset;
+    };
+    let noon = date.midnight().assume_offset(offset).unix_timestamp() + 12 * 60 * 60;
+    UtcOffset::from_whole_seconds(zone.offset_at(noon)).unwrap_or(offset)
+}
+
+/// Sunrise and sunset on the local `date` in minutes since midnight
+fn solar_day(date: Date, offset: UtcOffset, coordinates: Coordinates) -> Day {
+    let minutes = |event| {
+        let time = solar::event_time(coordinates, date, offset, event).ok()?;
+        Some(time.hour() as u16 * 60 + time.minute() as u16)
+    };
+
+    Day {
+        number: date.to_julian_day(),
+        weekday: date.weekday().number_days_from_monday(),
+        sunrise: minutes(SolarEvent::Sunrise),
+        sunset: minutes(SolarEvent::Sunset),
+    }
+}
+
+/// Moves the blind to the position of whichever of its rules fired most recently, a step a minute
+/// through gentle moves, at jittered times in vacation mode and lowered further while glare
+/// control sees the sun shining in, whenever that changes
+#[embassy_executor::task(pool_size = BLIND_COUNT)]
+async fn schedule_task(id: usize, sender: CommandSender, coordinates: Coordinates) -> ! {
     let system_time = SystemTime {};
 
     while !system_time.ntp_synchronized() {
-        debug!("schedule_task awaiting ntp sync");
+        debug!("schedule_task {id} awaiting ntp sync");
         Timer::after(Duration::from_secs(2)).await;
     }
 
-    let raise = Time::from_hms(12, 30, 0).unwrap();
-    let mut sunset: Option<OffsetDateTime> = None;
+    // Today followed by previous days
+    let mut days = [Day { number: 0, weekday: 0, sunrise: None, sunset: None }; SCHEDULE_LOOKBACK_DAYS];
+    let mut days_from = None;
     let mut state = None;
+    let mut activity = None;
 
     loop {
-        let state = &mut state;
-        let sunset = &mut sunset;
         let r: Result<(), Error> = async {
             let datetime = system_time.datetime()?;
-            let new_day = sunset.as_ref().map_or(true, |sunset| sunset.date() != datetime.date());
-
-            if new_day {
-                *sunset = Some(calculate_sunset(&datetime, coordinates)?);
+            let today = datetime.date();
+
+            // Recalculated on a change of offset too as the sun times are local
+            if days_from != Some((today, datetime.offset())) {
+                let zone = TIME_ZONE.lock().await;
+                let mut date = today;
+                for day in days.iter_mut() {
+                    *day = solar_day(date, offset_on(zone.as_ref(), date, datetime.offset()), coordinates);
+                    date = date.previous_day().ok_or(Error::Other("Date out of range"))?;
+                }
+                days_from = Some((today, datetime.offset()));
             }
 
-            let time = datetime.time();
+            let minute = datetime.hour() as u16 * 60 + datetime.minute() as u16;
+            let vacation = VACATION.lock().await.map(|vacation| vacation.for_blind(id));
+            let scenes = scene::positions_of(&SCENES.lock().await, id);
+            let rules = RULES[id].lock().await;
+            let last = schedule::last_event(&rules, &days, minute, vacation.as_ref(), &scenes);
+            let mut desired = last.map(|event| event.position);
+            // Glare control and gentle moves are always current, only rules go stale
+            let mut stale = last.is_some_and(|event| event.age > SCHEDULE_GRACE_MINUTES);
+            let mut current = Activity { ramping: false, shading: false };
+            if let Some(from) = desired {
+                let ramp = schedule::ramp_position(&rules, &days[0], minute, vacation.as_ref(), &scenes, from);
+                if ramp.is_some() {
+                    desired = ramp;
+                    stale = false;
+                    current.ramping = true;
+                }
+            }
+            drop(rules);
+
+            if let Some(glare) = *GLARE[id].lock().await {
+                let sun = solar::sun_position(coordinates, datetime);
+                let shaded = glare.shade(desired, sun.azimuth, sun.elevation);
+                stale &= shaded == desired;
+                desired = shaded;
+                current.shading = glare.in_sun(sun.azimuth, sun.elevation);
+            }
+            let previous = activity.replace(current);
+            // Sent on even if the last step already got there, so the blind saves its position
+            let ramp_finished = previous.is_some_and(|previous| previous.ramping && !current.ramping);
+
+            if let Some(held) = hold(id) {
+                let minute_start = datetime.unix_timestamp() - datetime.second() as i64;
+                let fired_at = last.map(|event| minute_start - event.age as i64 * 60);
+                // Steps of a gentle move change `desired` every minute without ending the hold
+                let changed = previous.is_some_and(|previous| previous.changes(current));
+
+                let Some(end) = held.end(datetime.unix_timestamp(), fired_at, changed) else {
+                    debug!("schedule_task {id} held since {}", held.since);
+                    if desired.is_some() {
+                        state = desired;
+                    }
+                    return Ok(());
+                };
 
-            let action = match (sunset.map(|v| v.time() <= time), raise <= time) {
-                // It's after sunset
-                (Some(true), _) => StepCommand::Lower,
-                // It's before sunset but after raise time
-                (Some(false), true) => StepCommand::Raise,
-                // It's after midnight but before raise time
-                (Some(false), false) => StepCommand::Lower,
+                info!("schedule_task {id} hold ended: {end:?}");
+                HOLD[id].lock(|hold| hold.set(None));
+                if let Some(pct) = desired {
+                    state = desired;
+                    let action = StepCommand::MoveTo(pct);
+                    info!("schedule_task {id} sending command: {action:?}");
+                    sender.send(action).await;
+                }
+                return Ok(());
+            }
 
-                (None, _) => unreachable!(),
+            let Some(pct) = desired else {
+                return Ok(());
             };
 
-            if *state != Some(action) {
-                *state = Some(action);
-                info!("schedule_task sending command: {action:?}");
+            if state.is_none() {
+                // First time round since starting up, check the blind is where the schedule says
+                // it should be rather than assuming the move happened
+                state = desired;
+                let MotorStatus { position, height, .. } = motor_status(id);
+                if position == percent_to_steps(pct, height) as i32 {
+                    info!("schedule_task {id} blind already at {pct}%");
+                } else if stale {
+                    info!("schedule_task {id} not catching up with {last:?}, older than {SCHEDULE_GRACE_MINUTES} minutes");
+                } else {
+                    let action = StepCommand::MoveTo(pct);
+                    info!("schedule_task {id} catching up with {last:?}, sending command: {action:?}");
+                    sender.send(action).await;
+                }
+            } else if state != desired || ramp_finished {
+                state = desired;
+                let action = if current.ramping { StepCommand::GentleStep(pct) } else { StepCommand::MoveTo(pct) };
+                info!("schedule_task {id} sending command: {action:?}");
                 sender.send(action).await;
             }
 
@@ -334,17 +1513,104 @@ async fn schedule_task(sender: Sender<'static, CriticalSectionRawMutex, StepComm
         }.await;
 
         if let Err(e) = r {
-            error!("schedule_task error: {e:?}");
+            error!("schedule_task {id} error: {e:?}");
         }
 
-        debug!("schedule_task sleeping");
-        Timer::after(Duration::from_secs(60)).await;
+        debug!("schedule_task {id} sleeping");
+        let _ = with_timeout(Duration::from_secs(60), RULES_CHANGED[id].wait()).await;
+    }
+}
+
+/// One-shot timers of all the blinds, soonest first, saved to NVS
+static TIMERS: Mutex<CriticalSectionRawMutex, Timers> = Mutex::new(Timers::new());
+/// Signalled when a timer is added or cancelled so `timer_task` works out when to wake again
+static TIMERS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+async fn load_timers(flash: &SharedFlash) -> Timers {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; one_shot::TIMERS_LEN];
+    match nvs.read_record(nvs::TIMERS_OFFSET, &mut buf) {
+        Ok(true) => one_shot::decode_timers(&buf),
+        Ok(false) => Timers::new(),
+        Err(e) => {
+            error!("Failed to read timers from NVS: {e:?}");
+            Timers::new()
+        },
+    }
+}
+
+async fn save_timers(flash: &SharedFlash, timers: &[OneShot]) -> Result<(), nvs::Error> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    nvs.write_record(nvs::TIMERS_OFFSET, &one_shot::encode_timers(timers))
+}
+
+/// Sends the command of each one-shot timer when it's due, holding off the schedule like any other
+/// manual command. Timers missed by more than `SCHEDULE_GRACE_MINUTES` while the device was off are
+/// dropped
+#[embassy_executor::task]
+async fn timer_task(senders: Senders, flash: &'static SharedFlash) -> ! {
+    let system_time = SystemTime {};
+
+    while !system_time.ntp_synchronized() {
+        debug!("timer_task awaiting ntp sync");
+        Timer::after(Duration::from_secs(2)).await;
+    }
+
+    loop {
+        let r: Result<Option<i64>, Error> = async {
+            let now = system_time.datetime()?.unix_timestamp();
+
+            let mut timers = TIMERS.lock().await;
+            let mut remaining = timers.clone();
+            let due = one_shot::take_due(&mut remaining, now);
+            if !due.is_empty() {
+                // Only taken once saved, otherwise they're left to fire on the next attempt rather
+                // than now and again after a restart
+                save_timers(flash, &remaining).await?;
+                *timers = remaining;
+            }
+            let next = timers.first().map(|timer| timer.due - now);
+            drop(timers);
+
+            for timer in due {
+                if now - timer.due > SCHEDULE_GRACE_MINUTES as i64 * 60 {
+                    warn!("timer_task dropping {timer:?}, missed by more than {SCHEDULE_GRACE_MINUTES} minutes");
+                    continue;
+                }
+                match senders.get(timer.blind as usize) {
+                    Some(sender) => {
+                        let action = StepCommand::MoveTo(timer.position);
+                        info!("timer_task sending command to blind {}: {action:?}", timer.blind);
+                        send_manual(*sender, timer.blind as usize, action).await;
+                    },
+                    None => warn!("timer_task dropping {timer:?}, no such blind"),
+                }
+            }
+
+            Ok(next)
+        }.await;
+
+        // Wakes at least every minute in case the clock is adjusted
+        let wait = match r {
+            Ok(Some(seconds)) => seconds.clamp(1, 60) as u64,
+            Ok(None) => 60,
+            Err(e) => {
+                error!("timer_task error: {e:?}");
+                60
+            },
+        };
+        let _ = with_timeout(Duration::from_secs(wait), TIMERS_CHANGED.wait()).await;
     }
 }
 
 #[embassy_executor::task]
 async fn ntp_task(mut client: ntp::Client, mut system_time: SystemTime) -> ! {
     let mut first_run = !system_time.configured();
+    let mut retry = NTP_MIN_RETRY;
     
     loop {
         let r: Result<(), Error> = async {
@@ -353,101 +1619,483 @@ async fn ntp_task(mut client: ntp::Client, mut system_time: SystemTime) -> ! {
             first_run = false;
             debug!("NTP updated. Offset = {offset}");
 
-            // In the UK the clocks go forward 1 hour at 1am on the last Sunday in March, and back 1 hour at 2am on the last Sunday in October. 
-            let datetime = system_time.datetime()?;
-            let year = datetime.year();
+            // The time may have jumped across a daylight saving transition
+            TIME_ZONE_CHANGED.signal(());
+            system_time.set_ntp_synchronized(true);
 
-            let forward = Date::from_calendar_date(year, Month::April, 1)?
-                .nth_prev_occurrence(Weekday::Sunday, 1);
-            let backward = Date::from_calendar_date(year, Month::November, 1)?
-                .nth_prev_occurrence(Weekday::Sunday, 1);
+            Ok(())
+        }.await;
 
-            debug!("Forward: {forward:?}, Backward: {backward}");
-            
-            let forward = OffsetDateTime::new_utc(forward, Time::from_hms(1, 0, 0)?);
-            let backward = OffsetDateTime::new_utc(backward, Time::from_hms(2, 0, 0)?);
-
-            let current_offset = system_time.offset()?;
-           
-            let change = if current_offset.is_positive() && datetime >= backward {
-                Some(UtcOffset::UTC)
-            } else if current_offset.is_utc() && datetime >= forward {
-                Some(UtcOffset::from_hms(1, 0, 0)?)
-            } else {
-                None
-            };
+        let wait = match r {
+            Ok(()) => {
+                retry = NTP_MIN_RETRY;
+                NTP_INTERVAL
+            },
+            Err(e) => {
+                error!("npt_task error: {e:?}");
+                let wait = retry;
+                retry = (retry * 2).min(NTP_INTERVAL);
+                wait
+            },
+        };
+
+        debug!("ntp_task sleeping for {}s", wait.as_secs());
+        Timer::after(wait).await;
+    }
+}
+
+/// Keeps the UTC offset of `system_time` in step with the time zone, waking at each daylight saving
+/// transition and whenever the zone is changed or NTP steps the clock
+#[embassy_executor::task]
+async fn time_zone_task(mut system_time: SystemTime) -> ! {
+    loop {
+        let now = (system_time.get_time_us() / 1_000_000) as i64;
+        let (offset, next) = match &*TIME_ZONE.lock().await {
+            Some(zone) => (zone.offset_at(now), zone.next_transition(now)),
+            None => (0, None),
+        };
 
-            if let Some(change) = change {
-                debug!("Changing offset to {change:?}");
-                system_time.set_offset(change);
+        if system_time.offset().ok().map(UtcOffset::whole_seconds) != Some(offset) {
+            match UtcOffset::from_whole_seconds(offset) {
+                Ok(offset) => {
+                    debug!("Changing offset to {offset:?}");
+                    system_time.set_offset(offset);
+                    // Local times of the rules have moved
+                    for changed in &RULES_CHANGED {
+                        changed.signal(());
+                    }
+                },
+                Err(e) => error!("time_zone_task error: {e:?}"),
             }
+        }
 
-            system_time.set_ntp_synchronized(true);
+        // Checked hourly in case the clock drifts past a transition before NTP corrects it
+        let wait = next.map_or(60 * 60, |next| (next - now).clamp(1, 60 * 60)) as u64;
+        let _ = with_timeout(Duration::from_secs(wait), TIME_ZONE_CHANGED.wait()).await;
+    }
+}
 
-            Ok(())
-        }.await;
+/// Converts a percentage of the travel into an absolute position. Percentages over 100 are clamped
+fn percent_to_steps(pct: u8, height: usize) -> usize {
+    height * pct.min(100) as usize / 100
+}
 
-        if let Err(e) = r {
-            error!("npt_task error: {e:?}");
-        }
+async fn load_i32(flash: &SharedFlash, offset: u32) -> Option<i32> {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    let mut buf = [0; size_of::<i32>()];
+    match nvs.read_record(offset, &mut buf) {
+        Ok(true) => Some(i32::from_le_bytes(buf)),
+        Ok(false) => None,
+        Err(e) => {
+            error!("Failed to read 0x{offset:x} from NVS: {e:?}");
+            None
+        },
+    }
+}
 
-        debug!("ntp_task sleeping");
-        Timer::after(Duration::from_secs(60 * 60)).await;
-        // Timer::after(Duration::from_secs(60)).await;
-        
+/// Returns whether `value` was saved
+async fn save_i32(flash: &SharedFlash, offset: u32, value: i32) -> bool {
+    let mut flash = flash.lock().await;
+    let mut nvs = Nvs::new(&mut flash);
+
+    match nvs.write_record(offset, &value.to_le_bytes()) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to save 0x{offset:x} to NVS: {e:?}");
+            false
+        },
+    }
+}
+
+/// Saves the position of blind `id` unless it's already `saved`, to spare the flash as every write
+/// erases the sector of positions
+async fn save_position(flash: &SharedFlash, id: usize, position: i32, saved: &mut i32) {
+    if position != *saved && save_i32(flash, nvs::position_offset(id), position).await {
+        *saved = position;
+    }
+}
+
+/// What a push button does
+#[cfg(any(feature = "button", feature = "buttons"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ButtonRole {
+    /// The only button, short presses alternate between raising and lowering
+    Toggle,
+    Up,
+    Down,
+}
+
+/// Blind the push buttons control
+#[cfg(any(feature = "button", feature = "buttons"))]
+const BUTTON_BLIND: usize = 0;
+#[cfg(any(feature = "button", feature = "buttons"))]
+const BUTTON_DEBOUNCE_MS: u64 = 30;
+/// Held this long the button jogs the blind until it's released
+#[cfg(any(feature = "button", feature = "buttons"))]
+const LONG_PRESS_MS: u64 = 600;
+/// Held this long the button starts calibration, or cancels it if it's already running
+#[cfg(any(feature = "button", feature = "buttons"))]
+const VERY_LONG_PRESS_MS: u64 = 5000;
+#[cfg(any(feature = "button", feature = "buttons"))]
+const BUTTON_POLL: Duration = Duration::from_millis(10);
+
+/// Direction a short or long press moves the blind in
+#[cfg(any(feature = "button", feature = "buttons"))]
+fn button_direction(role: ButtonRole, last_up: &mut bool) -> bool {
+    match role {
+        ButtonRole::Toggle => {
+            *last_up = !*last_up;
+            *last_up
+        },
+        ButtonRole::Up => true,
+        ButtonRole::Down => false,
+    }
+}
+
+/// Turns gestures on an active low push button into commands for [`BUTTON_BLIND`]. A press while
+/// the blind is moving stops it, otherwise a short press raises or lowers it (or marks the end of
+/// the travel while calibrating), a long press jogs until released and a very long press starts or
+/// cancels calibration. A very long press starts out as a long press, so the blind jogs until it's
+/// recognised
+#[cfg(any(feature = "button", feature = "buttons"))]
+#[embassy_executor::task(pool_size = 2)]
+async fn button_task(input: Input<'static>, role: ButtonRole, sender: CommandSender) -> ! {
+    let mut detector = GestureDetector::new(BUTTON_DEBOUNCE_MS, LONG_PRESS_MS, VERY_LONG_PRESS_MS);
+    // Toggle buttons lower the blind first if it's at the top, otherwise they raise it
+    let mut last_up = motor_status(BUTTON_BLIND).position <= 0;
+    // `last_up` before the long press, put back if it turns out to be very long
+    let mut before_jog = last_up;
+    // The press stopped a move so the rest of it is ignored
+    let mut consumed = false;
+
+    loop {
+        Timer::after(BUTTON_POLL).await;
+
+        let Some(gesture) = detector.update(input.is_low(), Instant::now().as_millis()) else {
+            continue;
+        };
+        debug!("{role:?} button: {gesture:?}");
+
+        let status = motor_status(BUTTON_BLIND);
+        match gesture {
+            Gesture::Pressed => {
+                consumed = status.moving;
+                if consumed {
+                    send_manual(sender, BUTTON_BLIND, StepCommand::Stop).await;
+                }
+            },
+            _ if consumed => {},
+            Gesture::Short if status.calibration.is_some() => send_manual(sender, BUTTON_BLIND, StepCommand::Mark).await,
+            Gesture::Short => {
+                let up = button_direction(role, &mut last_up);
+                send_manual(sender, BUTTON_BLIND, if up { StepCommand::Raise } else { StepCommand::Lower }).await;
+            },
+            Gesture::Long => {
+                // As far as the end of the travel, the release stops it sooner. While calibrating the
+                // ends aren't known so it's allowed to cover the old height from anywhere
+                before_jog = last_up;
+                let up = button_direction(role, &mut last_up);
+                let position = status.position.clamp(0, status.height as i32) as usize;
+                let n = match (status.calibration, up) {
+                    (Some(_), _) => status.height,
+                    (None, true) => position,
+                    (None, false) => status.height - position,
+                };
+                send_manual(sender, BUTTON_BLIND, if up { StepCommand::Forward(n) } else { StepCommand::Backward(n) }).await;
+            },
+            Gesture::VeryLong => {
+                last_up = before_jog;
+                send_manual(sender, BUTTON_BLIND, StepCommand::Stop).await;
+                if status.calibration.is_some() {
+                    send_manual(sender, BUTTON_BLIND, StepCommand::CancelCalibration).await;
+                } else {
+                    send_manual(sender, BUTTON_BLIND, StepCommand::Calibrate).await;
+                }
+            },
+            Gesture::Released => send_manual(sender, BUTTON_BLIND, StepCommand::Stop).await,
+        }
     }
 }
 
+#[cfg(feature = "stallguard")]
 #[embassy_executor::task]
+async fn stall_task(mut diag: Input<'static>) -> ! {
+    loop {
+        diag.wait_for_rising_edge().await;
+        trace!("DIAG raised");
+        STALL.signal(());
+    }
+}
+
+#[embassy_executor::task(pool_size = BLIND_COUNT)]
 async fn motor_task(
-    receiver: Receiver<'static, CriticalSectionRawMutex, StepCommand, 10>,
-    mut tmc_en: Output<'static>,
-    mut tmc_step: Output<'static>,
-    mut tmc_dir: Output<'static>,
-    microsteps: usize,
+    id: usize,
+    receiver: CommandReceiver,
+    flash: &'static SharedFlash,
+    mut actuator: Actuator,
+    mut endstops: Endstops,
+    stall_detection: bool,
 ) -> ! {
-    let mut raised = true;
+    let stall_detection = stall_detection || actuator.detects_stalls();
+
+    // Absolute position in steps, 0 = fully raised, height = fully lowered
+    let mut position = load_i32(flash, nvs::position_offset(id)).await.unwrap_or_else(|| {
+        warn!("No saved position in NVS for blind {id}, assuming it's raised");
+        0
+    });
+    let mut height = match load_i32(flash, nvs::height_offset(id)).await {
+        Some(height) if height > 0 => height as usize,
+        _ => {
+            warn!("No calibrated height in NVS for blind {id}, using default of {BLIND_HEIGHT}");
+            BLIND_HEIGHT
+        },
+    };
+    info!("Blind {id} position: {position}, height: {height}");
+    // Position last written to NVS, the sector is only erased again once the blind has moved
+    let mut saved = position;
+
+    let mut calibration = None;
+    let mut fault = None;
+
+    // Commands received while a move was in progress
+    let mut queued = Deque::<StepCommand, 10>::new();
+
     loop {
-        let msg = receiver.receive().await;
+        let status = MotorStatus { position, height, moving: false, calibration, fault };
+        MOTOR_STATUS[id].lock(|s| s.set(status));
+
+        let msg = match queued.pop_front() {
+            Some(msg) => msg,
+            None => receiver.receive().await,
+        };
         debug!("Step command: {:?}", msg);
 
-        match (raised, &msg) {
-            (true, StepCommand::Raise) => {
-                warn!("Already raised, skipping raise command");
+        // Any other command ends a gentle move, so the position its steps reached is saved
+        let gentle = matches!(msg, StepCommand::GentleStep(_));
+        if !gentle {
+            save_position(flash, id, position, &mut saved).await;
+        }
+
+        let target = match msg {
+            StepCommand::Stop => {
+                debug!("Stop received while idle");
                 continue;
             },
-            (false, StepCommand::Lower) => {
-                warn!("Already lowered, skipping lower command");
+            StepCommand::Calibrate => {
+                info!("Calibration started, jog the blind to the top and mark it");
+                calibration = Some(Calibration::Top);
                 continue;
             },
-            (false, StepCommand::Raise) | (true, StepCommand::Lower) => {
-                raised = !raised;
+            StepCommand::CancelCalibration => {
+                info!("Calibration cancelled");
+                calibration = None;
+                continue;
             },
-            _ => {},
+            StepCommand::Mark => {
+                match calibration {
+                    Some(Calibration::Top) => {
+                        position = 0;
+                        save_position(flash, id, position, &mut saved).await;
+                        calibration = Some(Calibration::Bottom);
+                        info!("Top marked, jog the blind to the bottom and mark it");
+                    },
+                    Some(Calibration::Bottom) if position <= 0 => {
+                        error!("Bottom must be below the top, position is {position}");
+                    },
+                    Some(Calibration::Bottom) => {
+                        height = position as usize;
+                        save_i32(flash, nvs::height_offset(id), position).await;
+                        calibration = None;
+                        info!("Calibration done, height: {height}");
+                    },
+                    None => warn!("Mark ignored, not calibrating"),
+                }
+                continue;
+            },
+            StepCommand::ClearFault => {
+                info!("Clearing fault: {fault:?}");
+                fault = None;
+                continue;
+            },
+            StepCommand::Raise
+            | StepCommand::Lower
+            | StepCommand::MoveTo(_)
+            | StepCommand::MoveToSteps(_)
+            | StepCommand::GentleStep(_)
+                if calibration.is_some() =>
+            {
+                warn!("Ignoring {msg:?} while calibrating");
+                continue;
+            },
+            StepCommand::Raise
+            | StepCommand::Lower
+            | StepCommand::MoveTo(_)
+            | StepCommand::MoveToSteps(_)
+            | StepCommand::GentleStep(_)
+                if fault.is_some() =>
+            {
+                warn!("Ignoring {msg:?} until {fault:?} is cleared");
+                continue;
+            },
+            StepCommand::Home if endstops.top.is_none() && !stall_detection => {
+                warn!("Can't home without a top endstop or stall detection");
+                continue;
+            },
+            // Overshoot the expected travel so homing still works if the position is out
+            StepCommand::Home => position - (height + height / 5) as i32,
+            // Jogs go no further than the travel, which also keeps them in range of the position
+            StepCommand::Forward(n) => position - n.min(height) as i32,
+            StepCommand::Backward(n) => position + n.min(height) as i32,
+            StepCommand::Raise => 0,
+            StepCommand::Lower => height as i32,
+            StepCommand::MoveTo(pct) | StepCommand::GentleStep(pct) => percent_to_steps(pct, height) as i32,
+            StepCommand::MoveToSteps(n) => n.min(height) as i32,
+        };
+
+        if target == position {
+            warn!("Already at {position}, skipping {msg:?} command");
+            continue;
         }
 
-        let (dir, n) = match msg {
-            StepCommand::Forward(n) => (Level::High, n),
-            StepCommand::Backward(n) => (Level::Low, n),
-            StepCommand::Raise => (Level::High, BLIND_HEIGHT),
-            StepCommand::Lower => (Level::Low, BLIND_HEIGHT),
+        let up = target < position;
+        let n = position.abs_diff(target);
+
+        if let Some(endstop) = endstops.towards(up) {
+            if endstop.settle().await {
+                if up {
+                    info!("Top endstop already triggered, zeroing position");
+                    position = 0;
+                    save_position(flash, id, position, &mut saved).await;
+                } else {
+                    warn!("Bottom endstop already triggered, skipping {msg:?} command");
+                }
+                continue;
+            }
+        }
+
+        let homing = msg == StepCommand::Home;
+        let sensorless = homing && endstops.top.is_none();
+        let profile = match (homing, sensorless) {
+            (true, true) => SENSORLESS_HOMING_PROFILE,
+            (true, false) => HOMING_PROFILE,
+            _ => MOTION_PROFILE,
         };
 
-        let n = n * microsteps;
+        MOTOR_STATUS[id].lock(|s| s.set(MotorStatus { moving: true, ..status }));
+
+        actuator.start_move(up, n, &profile);
+        let mut stopping = false;
+        let mut limit_hit = false;
+        let mut stalled = false;
+        let started = Instant::now();
+        STALL.reset();
+        loop {
+            if !stopping {
+                while let Ok(cmd) = receiver.try_receive() {
+                    if cmd == StepCommand::Stop {
+                        actuator.stop();
+                        stopping = true;
+                        queued.clear();
+                        info!("Stopping move of {n}");
+                        break;
+                    } else if queued.push_back(cmd).is_err() {
+                        warn!("Command queue full, dropping {cmd:?}");
+                    }
+                }
+            }
+
+            if let Some(endstop) = endstops.towards(up) {
+                if endstop.triggered() {
+                    limit_hit = true;
+                    break;
+                }
+            }
+
+            if stall_detection && STALL.signaled() {
+                if started.elapsed() < STALL_BLANKING {
+                    STALL.reset();
+                } else {
+                    stalled = true;
+                    break;
+                }
+            }
+
+            match actuator.advance().await {
+                Ok(Progress::Moving) => {},
+                Ok(Progress::Done) => break,
+                Ok(Progress::Stalled) => {
+                    stalled = true;
+                    break;
+                },
+                Err(e) => {
+                    error!("Actuator error, abandoning move: {e:?}");
+                    break;
+                },
+            }
+        }
+
+        let moved = actuator.end_move() as i32;
+        position = if up { position - moved } else { position + moved };
 
-        tmc_dir.set_level(dir);
-        tmc_en.set_low();
-        let fast = 1500;
-        let slow = 5000;
-        for i in 0..n {
-            let speed = if i < 100 || i >= n - 100 { slow } else { fast };
-            tmc_step.toggle();
-            Timer::after(Duration::from_micros(speed)).await;
+        match (limit_hit, stalled, up) {
+            (true, _, true) => {
+                info!("Top endstop reached, zeroing position (was {position})");
+                position = 0;
+            },
+            (true, _, false) => warn!("Bottom endstop reached at {position}"),
+            (false, true, true) if sensorless => {
+                info!("Stalled at the top, zeroing position (was {position})");
+                position = 0;
+            },
+            (false, true, _) => {
+                error!("Stall detected at {position}");
+                fault = Some(Fault::Stall { position });
+            },
+            (false, false, _) if homing => error!("Homing failed, top of travel not found"),
+            _ => {},
         }
-        tmc_en.set_high();
+        if !gentle || limit_hit || stalled {
+            save_position(flash, id, position, &mut saved).await;
+        }
+
+        debug!("Move done, position: {position}")
+    }
+}
 
-        debug!("Stepping done")
+/// Applies [`TMC_CONFIG`] and logs the state of the driver. Failures are logged rather than returned
+/// as the driver still works from the MS pins without the UART. Returns whether the configuration
+/// and StallGuard (when enabled) were applied, as stalls can only be trusted if both were
+#[cfg(feature = "tmc-uart")]
+async fn configure_driver(tmc: &mut Tmc<Uart<'static, Async>>) -> bool {
+    let configured = match with_timeout(TMC_TIMEOUT, tmc.configure(&TMC_CONFIG)).await {
+        Ok(Ok(())) => { info!("Driver configured: {TMC_CONFIG:?}"); true },
+        Ok(Err(e)) => { error!("Failed to configure driver: {e:?}"); false },
+        Err(_) => { error!("Timeout configuring driver"); false },
+    };
+
+    match with_timeout(TMC_TIMEOUT, tmc.take_gstat()).await {
+        Ok(Ok(gstat)) => debug!("Driver GSTAT: 0x{gstat:x}"),
+        Ok(Err(e)) => error!("Failed to read driver GSTAT: {e:?}"),
+        Err(_) => error!("Timeout reading driver GSTAT"),
+    }
+
+    #[cfg(feature = "stallguard")]
+    let configured = match with_timeout(TMC_TIMEOUT, tmc.configure_stall_guard(STALL_THRESHOLD, STALL_MIN_TSTEP)).await {
+        Ok(Ok(())) => { info!("StallGuard configured, threshold: {STALL_THRESHOLD}"); configured },
+        Ok(Err(e)) => { error!("Failed to configure StallGuard: {e:?}"); false },
+        Err(_) => { error!("Timeout configuring StallGuard"); false },
+    };
+
+    match with_timeout(TMC_TIMEOUT, tmc.drv_status()).await {
+        Ok(Ok(status)) if status.fault() => error!("Driver fault: {status:?}"),
+        Ok(Ok(status)) => debug!("Driver status: {status:?}"),
+        Ok(Err(e)) => error!("Failed to read driver DRV_STATUS: {e:?}"),
+        Err(_) => error!("Timeout reading driver DRV_STATUS"),
     }
+
+    configured
 }
 
 #[main]
@@ -633,38 +2281,176 @@ async fn main_fallible(spawner: &Spawner, peripherals: Peripherals) -> Result<()
         *UPDATE_PENDING.lock().await = true;
     }
 
-    let channel = mk_static!(Channel::<CriticalSectionRawMutex, StepCommand, 10>, Channel::new());
-    let sender = channel.sender();
-    let receiver = channel.receiver();
+    let flash = &*mk_static!(SharedFlash, Mutex::new(flash));
+
+    let channels = &*mk_static!([CommandChannel; BLIND_COUNT], [const { Channel::new() }; BLIND_COUNT]);
+    let senders: Senders = core::array::from_fn(|id| channels[id].sender());
 
-    let tmc_step = Output::new(peripherals.GPIO32, Level::High);
-    let tmc_dir = Output::new(peripherals.GPIO33, Level::High);
-    // en is active low
-    let tmc_en = Output::new(peripherals.GPIO25, Level::High);
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "dc-motor")] {
+            let actuator = {
+                let ledc = mk_static!(Ledc<'static>, Ledc::new(peripherals.LEDC));
+                ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+                let timer = mk_static!(ledc_timer::Timer<'static, LowSpeed>, ledc.timer(ledc_timer::Number::Timer0));
+                timer.configure(ledc_timer::config::Config {
+                    duty: ledc_timer::config::Duty::Duty10Bit,
+                    clock_source: ledc_timer::LSClockSource::APBClk,
+                    frequency: DC_PWM_KHZ.kHz(),
+                })?;
+                let mut pwm = ledc.channel(ledc_channel::Number::Channel0, peripherals.GPIO32);
+                pwm.configure(ledc_channel::config::Config {
+                    timer: &*timer,
+                    duty_pct: 0,
+                    pin_config: ledc_channel::config::PinConfig::PushPull,
+                })
+                .map_err(actuator::Error::from)?;
+
+                let pcnt = Pcnt::new(peripherals.PCNT);
+                // GPIO34 and 35 are input only with no internal pulls, the encoder needs external ones
+                let encoder_a = Input::new(peripherals.GPIO34, Pull::None);
+                let encoder_b = Input::new(peripherals.GPIO35, Pull::None);
+
+                // IN1 and IN2 of the H-bridge, both high brakes
+                let in1 = Output::new(peripherals.GPIO33, Level::High);
+                let in2 = Output::new(peripherals.GPIO25, Level::High);
+
+                Actuator::new(pwm, in1, in2, pcnt.unit0, encoder_a, encoder_b, DC_MOTOR_CONFIG)?
+            };
+        } else {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "rmt")] {
+                    let stepper = stepper::RmtStepper::new(peripherals.RMT, peripherals.GPIO32)?;
+                } else {
+                    let stepper = stepper::GpioStepper::new(Output::new(peripherals.GPIO32, Level::High));
+                }
+            }
+            let tmc_dir = Output::new(peripherals.GPIO33, Level::High);
+            // en is active low
+            let tmc_en = Output::new(peripherals.GPIO25, Level::High);
+            let batch_us = if cfg!(feature = "endstops") { ENDSTOP_BATCH_US } else { u32::MAX };
+            let actuator = Actuator::new(stepper, tmc_dir, tmc_en, MICROSTEPS as u32, batch_us);
+        }
+    }
 
-    // Microstep config
-    // Low, Low = 8
-    // High, Low = 2
-    // Low, High = 4
-    let _tmc_ms1 = Output::new(peripherals.GPIO27, Level::High);
-    let _tmc_ms2 = Output::new(peripherals.GPIO26, Level::Low);
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "endstops")] {
+            // Switches pull the inputs to ground when tripped
+            let endstops = Endstops {
+                top: Some(Endstop::new(Input::new(peripherals.GPIO13, Pull::Up), Level::Low)),
+                bottom: Some(Endstop::new(Input::new(peripherals.GPIO14, Pull::Up), Level::Low)),
+            };
+        } else {
+            let endstops = Endstops::none();
+        }
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "tmc-uart")] {
+            // The microsteps come from TMC_CONFIG so the MS pins only select the UART address
+            let (ms1, ms2) = (Level::from(TMC_ADDRESS & 0b01 != 0), Level::from(TMC_ADDRESS & 0b10 != 0));
+        } else {
+            // Microstep config
+            // Low, Low = 8
+            // High, Low = 2
+            // Low, High = 4
+            let (ms1, ms2) = (Level::High, Level::Low);
+        }
+    }
+    let _tmc_ms1 = Output::new(peripherals.GPIO27, ms1);
+    let _tmc_ms2 = Output::new(peripherals.GPIO26, ms2);
+
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "tmc-uart")] {
+            let (driver, stall_detection) = {
+                let mut uart_config = UartConfig::default();
+                uart_config.baudrate = TMC_BAUD;
+                let uart = Uart::new(peripherals.UART1, uart_config)?
+                    .with_rx(peripherals.GPIO16)
+                    .with_tx(peripherals.GPIO17)
+                    .into_async();
+                // TX and RX share the PDN_UART pin so everything sent is echoed back
+                let mut tmc = Tmc::new(uart, TMC_ADDRESS, true);
+                // Without StealthChop and a threshold DIAG means nothing, so stalls are ignored
+                let stall_detection = configure_driver(&mut tmc).await && cfg!(feature = "stallguard");
+                (Some(&*mk_static!(SharedDriver, Mutex::new(tmc))), stall_detection)
+            };
+        } else {
+            let (driver, stall_detection) = (None, false);
+        }
+    }
+
+    #[cfg(feature = "stallguard")]
+    if stall_detection {
+        spawner.must_spawn(stall_task(Input::new(peripherals.GPIO4, Pull::Down)));
+    } else {
+        error!("Stall detection disabled as the driver wasn't configured");
+    }
 
     
     let mut system_time = SystemTime {};
     
+    let time_zone = load_time_zone(flash).await;
     // Configured persists after restarts other than hard resets
     if !system_time.configured() {
-        // TODO: timezone
-        system_time.configure(UtcOffset::UTC);
+        let now = (system_time.get_time_us() / 1_000_000) as i64;
+        system_time.configure(UtcOffset::from_whole_seconds(time_zone.offset_at(now)).unwrap_or(UtcOffset::UTC));
+    }
+    *TIME_ZONE.lock().await = Some(time_zone);
+
+    let ntp_client = ntp::Client::new(stacks.ntp, NTP_SERVERS);
+
+    spawner.must_spawn(motor_task(0, channels[0].receiver(), flash, actuator, endstops, stall_detection));
+
+    // Further blinds share the MS pins with the first so they step at the same resolution
+    #[cfg(not(any(feature = "rmt", feature = "dc-motor")))]
+    {
+        use esp_hal::gpio::Pin;
+
+        let pins = [
+            (peripherals.GPIO18.degrade(), peripherals.GPIO19.degrade(), peripherals.GPIO21.degrade()),
+            (peripherals.GPIO22.degrade(), peripherals.GPIO23.degrade(), peripherals.GPIO5.degrade()),
+        ];
+        for (id, (step, dir, en)) in (1..BLIND_COUNT).zip(pins) {
+            let stepper = stepper::GpioStepper::new(Output::new(step, Level::High));
+            let actuator = Actuator::new(
+                stepper,
+                Output::new(dir, Level::High),
+                Output::new(en, Level::High),
+                MICROSTEPS as u32,
+                u32::MAX,
+            );
+            spawner.must_spawn(motor_task(id, channels[id].receiver(), flash, actuator, Endstops::none(), false));
+        }
     }
 
-    let ntp_client = ntp::Client::new_from_dns(stacks.ntp, NTP_SERVER).await?;
+    // Buttons pull the inputs to ground when pressed
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "buttons")] {
+            spawner.must_spawn(button_task(Input::new(peripherals.GPIO15, Pull::Up), ButtonRole::Up, senders[BUTTON_BLIND]));
+            spawner.must_spawn(button_task(Input::new(peripherals.GPIO2, Pull::Up), ButtonRole::Down, senders[BUTTON_BLIND]));
+        } else if #[cfg(feature = "button")] {
+            spawner.must_spawn(button_task(Input::new(peripherals.GPIO15, Pull::Up), ButtonRole::Toggle, senders[BUTTON_BLIND]));
+        }
+    }
 
-    spawner.must_spawn(motor_task(receiver, tmc_en, tmc_step, tmc_dir, 2));
     spawner.must_spawn(ntp_task(ntp_client, system_time));
-    spawner.must_spawn(schedule_task(sender, coordinates));
+    spawner.must_spawn(time_zone_task(SystemTime {}));
+    *VACATION.lock().await = load_vacation(flash).await;
+    *SCENES.lock().await = load_scenes(flash).await;
+    *TIMERS.lock().await = load_timers(flash).await;
+    spawner.must_spawn(timer_task(senders, flash));
+    for (id, sender) in senders.into_iter().enumerate() {
+        let rules = load_rules(flash, id).await.unwrap_or_else(|| {
+            warn!("No schedule in NVS for blind {id}, using the default");
+            default_rules()
+        });
+        info!("Blind {id} has {} schedule rules", rules.len());
+        *RULES[id].lock().await = rules;
+        *GLARE[id].lock().await = load_glare(flash, id).await;
+        spawner.must_spawn(schedule_task(id, sender, coordinates));
+    }
 
-    let app = &*mk_static!(AppRouter<AppProps>, AppProps { sender, coordinates }.build_app());
+    let app = &*mk_static!(AppRouter<AppProps>, AppProps { senders, flash, coordinates, driver, rng: rng.into() }.build_app());
     let config = &*mk_static!(
         picoserve::Config<Duration>,
         picoserve::Config::new(picoserve::Timeouts {
@@ -705,6 +2491,13 @@ pub enum Error {
 
     Http(http::Error),
 
+    Stepper(stepper::Error),
+    Actuator(actuator::Error),
+    #[cfg(feature = "dc-motor")]
+    Pwm(ledc_timer::Error),
+
+    Uart(uart::ConfigError),
+
     Utf8Error(Utf8Error),
 
     ParseIntError(ParseIntError),
@@ -750,6 +2543,31 @@ impl From<ota::Error> for Error {
     }
 }
 
+impl From<stepper::Error> for Error {
+    fn from(value: stepper::Error) -> Self {
+        Self::Stepper(value)
+    }
+}
+
+impl From<actuator::Error> for Error {
+    fn from(value: actuator::Error) -> Self {
+        Self::Actuator(value)
+    }
+}
+
+#[cfg(feature = "dc-motor")]
+impl From<ledc_timer::Error> for Error {
+    fn from(value: ledc_timer::Error) -> Self {
+        Self::Pwm(value)
+    }
+}
+
+impl From<uart::ConfigError> for Error {
+    fn from(value: uart::ConfigError) -> Self {
+        Self::Uart(value)
+    }
+}
+
 impl From<http::Error> for Error {
     fn from(value: http::Error) -> Self {
         Self::Http(value)
@@ -0,0 +1,139 @@
+//! Hardware independent parts of driving a DC motor with a quadrature encoder
+
+/// Extends the wrapping hardware pulse counter into an unbounded count. The counter has to be
+/// sampled often enough that it never moves more than half its range between samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderCount {
+    /// The hardware counter resets to 0 when it reaches +/- this value
+    limit: i32,
+    last_raw: i32,
+    count: i64,
+}
+
+impl EncoderCount {
+    pub const fn new(limit: i16) -> Self {
+        Self { limit: limit as i32, last_raw: 0, count: 0 }
+    }
+
+    /// Feeds a raw counter value and returns the total count
+    pub fn update(&mut self, raw: i16) -> i64 {
+        let raw = raw as i32;
+        let mut delta = raw - self.last_raw;
+
+        // The counter went past one of the limits and wrapped back through 0
+        if delta > self.limit / 2 {
+            delta -= self.limit;
+        } else if delta < -self.limit / 2 {
+            delta += self.limit;
+        }
+
+        self.last_raw = raw;
+        self.count += delta as i64;
+        self.count
+    }
+
+    pub fn count(&self) -> i64 {
+        self.count
+    }
+}
+
+/// PWM duty (in percent) over the course of a move. The duty ramps up from `min_duty` over the soft
+/// start period and back down again over the last `slowdown` counts so the motor doesn't slam into
+/// the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DutyRamp {
+    pub min_duty: u8,
+    pub max_duty: u8,
+    pub soft_start_ms: u64,
+    pub slowdown: u32,
+}
+
+impl DutyRamp {
+    /// Duty for a move that started `elapsed_ms` ago with `remaining` counts still to go
+    pub fn duty(&self, elapsed_ms: u64, remaining: u32) -> u8 {
+        let start = self.interpolate(elapsed_ms, self.soft_start_ms);
+        let end = self.interpolate(remaining as u64, self.slowdown as u64);
+        start.min(end)
+    }
+
+    /// Duty while stopping, falling from `from` to 0 over the soft start period
+    pub fn stopping_duty(&self, from: u8, elapsed_ms: u64) -> u8 {
+        if elapsed_ms >= self.soft_start_ms {
+            0
+        } else {
+            (from as u64 * (self.soft_start_ms - elapsed_ms) / self.soft_start_ms) as u8
+        }
+    }
+
+    fn interpolate(&self, value: u64, over: u64) -> u8 {
+        let (min, max) = (self.min_duty.min(self.max_duty), self.max_duty);
+        if value >= over {
+            max
+        } else {
+            min + ((max - min) as u64 * value / over) as u8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAMP: DutyRamp = DutyRamp { min_duty: 20, max_duty: 80, soft_start_ms: 500, slowdown: 100 };
+
+    #[test]
+    fn counts_without_wrapping() {
+        let mut encoder = EncoderCount::new(1000);
+        assert_eq!(encoder.update(100), 100);
+        assert_eq!(encoder.update(350), 350);
+        assert_eq!(encoder.update(-50), -50);
+        assert_eq!(encoder.count(), -50);
+    }
+
+    #[test]
+    fn wraps_up() {
+        let mut encoder = EncoderCount::new(1000);
+        encoder.update(400);
+        encoder.update(900);
+        // Reached 1000 and reset to 0, then counted on to 100
+        assert_eq!(encoder.update(100), 1100);
+        assert_eq!(encoder.update(600), 1600);
+    }
+
+    #[test]
+    fn wraps_down() {
+        let mut encoder = EncoderCount::new(1000);
+        encoder.update(-400);
+        encoder.update(-900);
+        // Reached -1000 and reset to 0, then counted on to -100
+        assert_eq!(encoder.update(-100), -1100);
+        // And back up through the limit
+        assert_eq!(encoder.update(-900), -900);
+        assert_eq!(encoder.update(-400), -400);
+    }
+
+    #[test]
+    fn soft_start() {
+        assert_eq!(RAMP.duty(0, 1000), 20);
+        assert_eq!(RAMP.duty(250, 1000), 50);
+        assert_eq!(RAMP.duty(500, 1000), 80);
+        assert_eq!(RAMP.duty(10_000, 1000), 80);
+    }
+
+    #[test]
+    fn slowdown() {
+        assert_eq!(RAMP.duty(10_000, 100), 80);
+        assert_eq!(RAMP.duty(10_000, 50), 50);
+        assert_eq!(RAMP.duty(10_000, 0), 20);
+        // The lower of the two while a short move is still starting
+        assert_eq!(RAMP.duty(250, 25), 35);
+    }
+
+    #[test]
+    fn stop() {
+        assert_eq!(RAMP.stopping_duty(80, 0), 80);
+        assert_eq!(RAMP.stopping_duty(80, 250), 40);
+        assert_eq!(RAMP.stopping_duty(80, 500), 0);
+        assert_eq!(RAMP.stopping_duty(80, 10_000), 0);
+    }
+}
@@ -0,0 +1,71 @@
+//! Time based debouncing of digital inputs
+
+/// Tracks the settled state of a noisy input. The state only changes once the raw input has held a
+/// new level for the whole debounce period
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Debouncer {
+    period_ms: u64,
+    stable: bool,
+    candidate: bool,
+    since_ms: u64,
+}
+
+impl Debouncer {
+    pub const fn new(period_ms: u64, initial: bool) -> Self {
+        Self { period_ms, stable: initial, candidate: initial, since_ms: 0 }
+    }
+
+    /// Feeds a raw sample taken at `now_ms` and returns the settled state
+    pub fn update(&mut self, raw: bool, now_ms: u64) -> bool {
+        if raw != self.candidate {
+            self.candidate = raw;
+            self.since_ms = now_ms;
+        }
+
+        if self.candidate != self.stable && now_ms.saturating_sub(self.since_ms) >= self.period_ms {
+            self.stable = self.candidate;
+        }
+
+        self.stable
+    }
+
+    /// The settled state as of the last update
+    pub fn state(&self) -> bool {
+        self.stable
+    }
+
+    pub fn period_ms(&self) -> u64 {
+        self.period_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glitch_rejected() {
+        let mut debouncer = Debouncer::new(10, false);
+        assert!(!debouncer.update(true, 100));
+        assert!(!debouncer.update(true, 109));
+        assert!(!debouncer.update(false, 110));
+        assert!(!debouncer.update(false, 130));
+        assert!(!debouncer.state());
+    }
+
+    #[test]
+    fn changes_once_stable() {
+        let mut debouncer = Debouncer::new(10, false);
+        assert!(!debouncer.update(true, 100));
+        assert!(!debouncer.update(true, 105));
+        assert!(debouncer.update(true, 110));
+        assert!(debouncer.state());
+
+        // And back again, the period restarting on each bounce
+        assert!(debouncer.update(false, 200));
+        assert!(debouncer.update(true, 205));
+        assert!(debouncer.update(false, 206));
+        assert!(debouncer.update(false, 215));
+        assert!(!debouncer.update(false, 216));
+    }
+}
@@ -0,0 +1,62 @@
+//! Limit switches at the ends of the blind travel
+
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{Input, Level};
+
+use crate::debounce::Debouncer;
+
+/// How long a switch has to hold a new level before it's believed. Moves sample the switch every
+/// 3-5ms (see `ENDSTOP_BATCH_US` in the binary) so this is a couple of samples at the slowest
+const DEBOUNCE_MS: u64 = 10;
+
+pub struct Endstop {
+    input: Input<'static>,
+    active: Level,
+    debouncer: Debouncer,
+}
+
+impl Endstop {
+    /// `active` is the input level when the switch is tripped
+    pub fn new(input: Input<'static>, active: Level) -> Self {
+        let debouncer = Debouncer::new(DEBOUNCE_MS, input.level() == active);
+        Self { input, active, debouncer }
+    }
+
+    /// Samples the switch and returns the debounced state. Needs calling at least a couple of times
+    /// per debounce period (10ms) to notice changes promptly
+    pub fn triggered(&mut self) -> bool {
+        let raw = self.input.level() == self.active;
+        self.debouncer.update(raw, Instant::now().as_millis())
+    }
+
+    /// Samples the switch for a full debounce period, for when it hasn't been polled recently
+    pub async fn settle(&mut self) -> bool {
+        let end = Instant::now() + Duration::from_millis(self.debouncer.period_ms() + 1);
+        while Instant::now() < end {
+            self.triggered();
+            Timer::after(Duration::from_millis(1)).await;
+        }
+        self.triggered()
+    }
+}
+
+/// The optional switches at either end of the travel
+pub struct Endstops {
+    pub top: Option<Endstop>,
+    pub bottom: Option<Endstop>,
+}
+
+impl Endstops {
+    pub const fn none() -> Self {
+        Self { top: None, bottom: None }
+    }
+
+    /// The switch at the end of the travel the blind is moving towards
+    pub fn towards(&mut self, up: bool) -> Option<&mut Endstop> {
+        if up {
+            self.top.as_mut()
+        } else {
+            self.bottom.as_mut()
+        }
+    }
+}
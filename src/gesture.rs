@@ -0,0 +1,125 @@
+//! Press gestures on a push button
+
+use crate::debounce::Debouncer;
+
+/// Something the button did, reported by [`GestureDetector::update`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// The button went down. Always followed by `Short`, or by `Long` (then possibly `VeryLong`) and
+    /// `Released`
+    Pressed,
+    /// Released before the long press threshold
+    Short,
+    /// Still held at the long press threshold
+    Long,
+    /// Still held at the very long press threshold, always preceded by `Long`
+    VeryLong,
+    /// Released after a `Long` press
+    Released,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Held {
+    Short,
+    Long,
+    VeryLong,
+}
+
+/// Turns raw samples of a button into [`Gesture`]s. Needs sampling at least a couple of times per
+/// debounce period
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GestureDetector {
+    debouncer: Debouncer,
+    long_ms: u64,
+    very_long_ms: u64,
+    /// When the current press started and how long it's been reported as held for
+    press: Option<(u64, Held)>,
+}
+
+impl GestureDetector {
+    pub const fn new(debounce_ms: u64, long_ms: u64, very_long_ms: u64) -> Self {
+        Self { debouncer: Debouncer::new(debounce_ms, false), long_ms, very_long_ms, press: None }
+    }
+
+    /// Feeds a raw sample (true when pressed) taken at `now_ms`
+    pub fn update(&mut self, raw: bool, now_ms: u64) -> Option<Gesture> {
+        let pressed = self.debouncer.update(raw, now_ms);
+
+        match (self.press, pressed) {
+            (None, true) => {
+                self.press = Some((now_ms, Held::Short));
+                Some(Gesture::Pressed)
+            },
+            (Some((_, held)), false) => {
+                self.press = None;
+                Some(if held == Held::Short { Gesture::Short } else { Gesture::Released })
+            },
+            (Some((since, held)), true) => {
+                let duration = now_ms.saturating_sub(since);
+                let (held, gesture) = match held {
+                    Held::Short if duration >= self.long_ms => (Held::Long, Gesture::Long),
+                    Held::Long if duration >= self.very_long_ms => (Held::VeryLong, Gesture::VeryLong),
+                    _ => return None,
+                };
+                self.press = Some((since, held));
+                Some(gesture)
+            },
+            (None, false) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `raw` every 10ms from `from_ms` up to but not including `to_ms`, collecting the gestures
+    fn feed(detector: &mut GestureDetector, raw: bool, from_ms: u64, to_ms: u64) -> Vec<Gesture> {
+        (from_ms..to_ms).step_by(10).filter_map(|now_ms| detector.update(raw, now_ms)).collect()
+    }
+
+    fn detector() -> GestureDetector {
+        GestureDetector::new(30, 600, 5000)
+    }
+
+    #[test]
+    fn short() {
+        let mut detector = detector();
+        assert_eq!(feed(&mut detector, false, 0, 100), []);
+        assert_eq!(feed(&mut detector, true, 100, 300), [Gesture::Pressed]);
+        assert_eq!(feed(&mut detector, false, 300, 400), [Gesture::Short]);
+    }
+
+    #[test]
+    fn long() {
+        let mut detector = detector();
+        assert_eq!(feed(&mut detector, true, 0, 620), [Gesture::Pressed]);
+        // Pressed once debounced at 30ms so long from 630ms
+        assert_eq!(feed(&mut detector, true, 620, 1000), [Gesture::Long]);
+        assert_eq!(feed(&mut detector, false, 1000, 1100), [Gesture::Released]);
+    }
+
+    #[test]
+    fn very_long() {
+        let mut detector = detector();
+        assert_eq!(feed(&mut detector, true, 0, 6000), [Gesture::Pressed, Gesture::Long, Gesture::VeryLong]);
+        assert_eq!(feed(&mut detector, true, 6000, 10_000), []);
+        assert_eq!(feed(&mut detector, false, 10_000, 10_100), [Gesture::Released]);
+    }
+
+    #[test]
+    fn bounce_ignored() {
+        let mut detector = detector();
+        // Shorter than the debounce period, on the way down and back up
+        for now_ms in [0, 10, 20] {
+            assert_eq!(detector.update(now_ms != 10, now_ms), None);
+        }
+        assert_eq!(feed(&mut detector, false, 30, 100), []);
+
+        assert_eq!(feed(&mut detector, true, 100, 300), [Gesture::Pressed]);
+        assert_eq!(detector.update(false, 300), None);
+        assert_eq!(detector.update(true, 310), None);
+        assert_eq!(feed(&mut detector, true, 320, 400), []);
+        assert_eq!(feed(&mut detector, false, 400, 500), [Gesture::Short]);
+    }
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(sync_unsafe_cell)]
 #![feature(impl_trait_in_assoc_type)]
 #![feature(never_type)]
@@ -13,10 +13,40 @@ macro_rules! mk_static {
     }};
 }
 
+pub mod dc_motor;
+pub mod debounce;
+pub mod gesture;
+pub mod motion;
+pub mod one_shot;
+pub mod pulse;
+pub mod partitions;
+pub mod schedule;
+pub mod scene;
+pub mod solar;
+pub mod tmc;
+pub mod tz;
+
+// The modules using the peripherals are left out without a chip feature, so the rest can be
+// tested on the host
+#[cfg(feature = "esp-hal")]
+pub mod actuator;
+
+#[cfg(feature = "esp-hal")]
+pub mod endstop;
+
+#[cfg(feature = "esp-println")]
 pub mod logging;
+
+#[cfg(feature = "esp-hal")]
 pub mod rng;
-pub mod partitions;
+
+#[cfg(feature = "esp-hal")]
 pub mod rtc;
+
+#[cfg(feature = "esp-hal")]
+pub mod stepper;
+
+#[cfg(feature = "esp-hal")]
 pub mod system_time;
 
 #[cfg(feature = "storage")]
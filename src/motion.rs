@@ -0,0 +1,344 @@
+//! Step timing for acceleration limited moves
+//!
+//! The planner works purely in steps and seconds so it has no dependency on the hardware. It
+//! produces the interval before each step of a move, accelerating along a jerk limited (S-curve)
+//! ramp, cruising and then decelerating along the mirror image of the same ramp. Moves too short to
+//! reach the cruise speed peak at whatever speed lets them decelerate in time.
+
+use libm::sqrtf;
+
+/// Newton iterations used to find the time at which a step happens on the ramp
+const NEWTON_ITERATIONS: usize = 8;
+/// Bisection iterations used to find the peak speed of a short move
+const BISECT_ITERATIONS: usize = 24;
+
+const US_PER_S: f32 = 1_000_000.;
+
+/// Limits describing the motion profile. Speeds are in steps/s, acceleration in steps/s² and jerk
+/// in steps/s³
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionProfile {
+    /// Speed moves start and end at. Must be above 0 as the first step would never happen otherwise
+    pub start_speed: f32,
+    /// Cruise speed
+    pub max_speed: f32,
+    /// Maximum acceleration
+    pub acceleration: f32,
+    /// Maximum rate of change of acceleration. 0 gives a plain trapezoidal profile
+    pub jerk: f32,
+}
+
+impl MotionProfile {
+    /// A profile that steps at a constant speed with no ramp at all
+    pub const fn constant(speed: f32) -> Self {
+        Self { start_speed: speed, max_speed: speed, acceleration: 0., jerk: 0. }
+    }
+
+    /// Scales every limit by `factor`, for converting a profile between units
+    pub fn scaled(&self, factor: f32) -> Self {
+        Self {
+            start_speed: self.start_speed * factor,
+            max_speed: self.max_speed * factor,
+            acceleration: self.acceleration * factor,
+            jerk: self.jerk * factor,
+        }
+    }
+
+    fn start_speed(&self) -> f32 {
+        self.start_speed.max(1.)
+    }
+
+    fn max_speed(&self) -> f32 {
+        self.max_speed.max(self.start_speed())
+    }
+}
+
+/// A ramp from the start speed up to a peak speed with the acceleration reaching 0 again at the
+/// peak. Deceleration uses the same ramp in reverse
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Ramp {
+    v0: f32,
+    peak: f32,
+    jerk: f32,
+    accel: f32,
+    /// Duration of each of the jerk phases
+    tj: f32,
+    /// Duration of the constant acceleration phase
+    ta: f32,
+}
+
+impl Ramp {
+    fn new(profile: &MotionProfile, peak: f32) -> Self {
+        let v0 = profile.start_speed();
+        let dv = peak - v0;
+        let a = profile.acceleration;
+        let j = profile.jerk;
+
+        let (tj, ta, accel) = if dv <= 0. || a <= 0. {
+            (0., 0., 0.)
+        } else if j <= 0. {
+            (0., dv / a, a)
+        } else if dv >= a * a / j {
+            let tj = a / j;
+            (tj, dv / a - tj, a)
+        } else {
+            let tj = sqrtf(dv / j);
+            (tj, 0., j * tj)
+        };
+
+        Self { v0, peak: if accel > 0. { peak } else { v0 }, jerk: j, accel, tj, ta }
+    }
+
+    fn duration(&self) -> f32 {
+        2. * self.tj + self.ta
+    }
+
+    /// Distance covered by the ramp. It's symmetric so the average speed is half way between the
+    /// start and peak
+    fn distance(&self) -> f32 {
+        (self.v0 + self.peak) / 2. * self.duration()
+    }
+
+    /// Returns the (distance, speed) at time `t` from the start of the ramp. Past the end of the
+    /// ramp it continues at the peak speed
+    fn state(&self, t: f32) -> (f32, f32) {
+        let Self { v0, accel: a, tj, ta, .. } = *self;
+        let j = if tj > 0. { self.jerk } else { 0. };
+        let t = t.max(0.);
+
+        if t <= tj {
+            return (v0 * t + j * t * t * t / 6., v0 + j * t * t / 2.);
+        }
+        let v1 = v0 + a * tj / 2.;
+        let s1 = v0 * tj + a * tj * tj / 6.;
+
+        let t = t - tj;
+        if t <= ta {
+            return (s1 + v1 * t + a * t * t / 2., v1 + a * t);
+        }
+        let v2 = v1 + a * ta;
+        let s2 = s1 + v1 * ta + a * ta * ta / 2.;
+
+        let t = t - ta;
+        if t <= tj {
+            return (
+                s2 + v2 * t + a * t * t / 2. - j * t * t * t / 6.,
+                v2 + a * t - j * t * t / 2.,
+            );
+        }
+
+        (self.distance() + self.peak * (t - tj), self.peak)
+    }
+
+    /// Time at which the ramp has covered `distance`, starting the search from `guess`
+    fn time_at(&self, distance: f32, guess: f32) -> f32 {
+        let mut t = guess;
+        for _ in 0..NEWTON_ITERATIONS {
+            let (s, v) = self.state(t);
+            let dt = (s - distance) / v;
+            t = (t - dt).max(0.);
+            if dt.abs() < 1e-7 {
+                break;
+            }
+        }
+        t
+    }
+}
+
+/// Generates the interval in microseconds before each step of a move
+///
+/// ```ignore
+/// let mut planner = StepPlanner::new(&profile, 1000);
+/// while let Some(interval) = planner.next() {
+///     step();
+///     Timer::after_micros(interval as u64).await;
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StepPlanner {
+    ramp: Ramp,
+    ramp_steps: u32,
+    steps: u32,
+    step: u32,
+    guess: f32,
+}
+
+impl StepPlanner {
+    pub fn new(profile: &MotionProfile, steps: u32) -> Self {
+        let full = Ramp::new(profile, profile.max_speed());
+
+        // Short moves don't have room to reach the cruise speed so find the highest peak that
+        // leaves room to decelerate again
+        let ramp = if 2. * full.distance() <= steps as f32 {
+            full
+        } else {
+            let (mut low, mut high) = (profile.start_speed(), profile.max_speed());
+            for _ in 0..BISECT_ITERATIONS {
+                let mid = (low + high) / 2.;
+                if 2. * Ramp::new(profile, mid).distance() <= steps as f32 {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+            Ramp::new(profile, low)
+        };
+
+        let ramp_steps = (ramp.distance() as u32).min(steps / 2);
+
+        Self { ramp, ramp_steps, steps, step: 0, guess: 0. }
+    }
+
+    /// Total number of steps the move will take. This shrinks if [`stop`][Self::stop] is called
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Number of steps generated so far
+    pub fn steps_taken(&self) -> u32 {
+        self.step
+    }
+
+    /// Peak speed the move will reach in steps/s
+    pub fn peak_speed(&self) -> f32 {
+        self.ramp.peak
+    }
+
+    /// Decelerates to a stop as quickly as the profile allows. The stop is rounded up to a multiple
+    /// of `multiple_of` steps (capped at the original length) so callers can stop on whole steps
+    /// when microstepping
+    pub fn stop(&mut self, multiple_of: u32) {
+        let i = self.step;
+        let decel = i.min(self.ramp_steps);
+        let end = (i + decel).div_ceil(multiple_of.max(1)) * multiple_of.max(1);
+        self.steps = self.steps.min(end);
+    }
+
+    /// Interval before step `j` of the ramp (1 based)
+    fn ramp_interval(&mut self, j: u32) -> f32 {
+        let before = self.ramp.time_at((j - 1) as f32, self.guess);
+        let after = self.ramp.time_at(j as f32, before);
+        self.guess = before;
+        after - before
+    }
+}
+
+impl Iterator for StepPlanner {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.step >= self.steps {
+            return None;
+        }
+
+        let i = self.step;
+        // Distance from whichever end of the move is closest
+        let j = (i + 1).min(self.steps - i);
+        let interval = if j > self.ramp_steps {
+            1. / self.ramp.peak
+        } else {
+            self.ramp_interval(j)
+        };
+
+        self.step += 1;
+        Some((interval * US_PER_S) as u32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.steps - self.step) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: MotionProfile = MotionProfile { start_speed: 200., max_speed: 2000., acceleration: 8000., jerk: 80_000. };
+
+    /// Interval in microseconds at `speed` in steps/s
+    fn interval(speed: f32) -> u32 {
+        (US_PER_S / speed) as u32
+    }
+
+    #[test]
+    fn empty() {
+        let mut planner = StepPlanner::new(&PROFILE, 0);
+        assert_eq!(planner.steps(), 0);
+        assert_eq!(planner.next(), None);
+    }
+
+    #[test]
+    fn single_step() {
+        let intervals: heapless::Vec<u32, 4> = StepPlanner::new(&PROFILE, 1).collect();
+        assert_eq!(intervals.len(), 1);
+        // No room to speed up, so it's taken at about the start speed
+        assert!(intervals[0].abs_diff(interval(PROFILE.start_speed)) <= 50, "{intervals:?}");
+    }
+
+    #[test]
+    fn triangular() {
+        let planner = StepPlanner::new(&PROFILE, 200);
+        assert!(planner.peak_speed() < PROFILE.max_speed);
+        assert!(planner.peak_speed() > PROFILE.start_speed);
+
+        let intervals: heapless::Vec<u32, 200> = planner.collect();
+        assert_eq!(intervals.len(), 200);
+        // Speeds up to the middle then slows down along the same ramp
+        for pair in intervals[..100].windows(2) {
+            assert!(pair[1] <= pair[0] + 1, "{pair:?}");
+        }
+        for (up, down) in intervals.iter().zip(intervals.iter().rev()) {
+            assert!(up.abs_diff(*down) <= 2, "{up} {down}");
+        }
+        assert!(intervals[99] >= interval(PROFILE.max_speed));
+    }
+
+    #[test]
+    fn cruise() {
+        let mut planner = StepPlanner::new(&PROFILE, 2000);
+        assert_eq!(planner.peak_speed(), PROFILE.max_speed);
+        assert_eq!(planner.nth(1000), Some(interval(PROFILE.max_speed)));
+    }
+
+    #[test]
+    fn stop_while_cruising() {
+        let mut planner = StepPlanner::new(&PROFILE, 10_000);
+        let first = planner.next().unwrap();
+        assert_eq!(planner.nth(998), Some(interval(PROFILE.max_speed)));
+
+        planner.stop(1);
+        let ramp_steps = planner.ramp_steps;
+        assert_eq!(planner.steps(), 1000 + ramp_steps);
+
+        let mut last = interval(PROFILE.max_speed);
+        let mut count = 0;
+        for interval in planner.by_ref() {
+            assert!(interval + 1 >= last, "{interval} after {last}");
+            last = interval;
+            count += 1;
+        }
+        assert_eq!(count, ramp_steps);
+        // Ends at the speed it started at
+        assert!(last.abs_diff(first) <= 2, "{last} {first}");
+    }
+
+    #[test]
+    fn stop_while_decelerating() {
+        let mut planner = StepPlanner::new(&PROFILE, 10_000);
+        planner.nth(10_000 - 11).unwrap();
+        // Already slowing down as fast as it can, so it still stops where it was going to
+        planner.stop(1);
+        assert_eq!(planner.steps(), 10_000);
+        assert_eq!(planner.count(), 10);
+    }
+
+    #[test]
+    fn stop_on_multiple() {
+        let mut planner = StepPlanner::new(&PROFILE, 10_000);
+        planner.nth(1000).unwrap();
+        planner.stop(16);
+        assert_eq!(planner.steps() % 16, 0);
+        assert!(planner.steps() >= 1001 + planner.ramp_steps);
+    }
+}
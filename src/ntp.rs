@@ -12,7 +12,16 @@ use embassy_net::dns::DnsQueryType;
 use core::net::Ipv4Addr;
 use core::net::SocketAddrV4;
 
+use embassy_time::{with_timeout, Duration, Instant};
+use heapless::Vec;
+
 const NTP_PORT: u16 = 123;
+/// Addresses kept from resolving all the server names
+const MAX_ADDRESSES: usize = 8;
+/// Time allowed for a server to answer before trying the next
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The server names are resolved again after this long, as pools hand out different servers over time
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
 
 use log::*;
 
@@ -74,19 +83,25 @@ pub struct Client {
     rx_buffer: [u8; UDP_BUFFER_SIZE],
     tx_buffer: [u8; UDP_BUFFER_SIZE],
 
-    ntp_socket: SocketAddrV4,
+    /// Comma separated server names
+    servers: &'static str,
+    /// Addresses of all the servers, tried in turn
+    addresses: Vec<Ipv4Addr, MAX_ADDRESSES>,
+    /// Index into `addresses` of the next server to ask
+    next: usize,
+    /// When `addresses` was last resolved, `None` to resolve them before the next request
+    resolved_at: Option<Instant>,
 }
 
 impl Client {
-    /// Create a new client
-    pub fn new(stack: NtpStack, ntp_ip: Ipv4Addr) -> Self {
+    /// Create a new client for `servers`, a comma separated list of names such as
+    /// `pool.ntp.org,time.cloudflare.com`. They're resolved before the first request
+    pub fn new(stack: NtpStack, servers: &'static str) -> Self {
         let rx_meta =  [PacketMetadata::EMPTY; PACKET_METADATA_N];
         let rx_buffer = [0u8; UDP_BUFFER_SIZE];
         let tx_meta = [PacketMetadata::EMPTY; PACKET_METADATA_N];
         let tx_buffer = [0u8; UDP_BUFFER_SIZE]; 
 
-        let ntp_socket = SocketAddrV4::new(ntp_ip, NTP_PORT);
-
         Self {
             stack,
             rx_meta,
@@ -94,31 +109,84 @@ impl Client {
             tx_meta,
             tx_buffer,
 
-            ntp_socket,
+            servers,
+            addresses: Vec::new(),
+            next: 0,
+            resolved_at: None,
         }
     }
 
-    pub async fn new_from_dns(stack: NtpStack, dns_name: &str) -> Result<Self, Error> {
+    /// Resolves every server name, keeping the previous addresses if none resolve
+    async fn resolve(&mut self) -> Result<(), Error> {
         trace!("Create DNS socket");
-        let dns_socket = DnsSocket::new(stack.stack());
-        
-        trace!("Resolving dns name");
-        let addresses = dns_socket.query(dns_name, DnsQueryType::A).await?;
-        if addresses.len() == 0 {
-            Err(Error::Other("DNS returned 0 A addresses"))?;
+        let dns_socket = DnsSocket::new(self.stack.stack());
+
+        let mut addresses = Vec::<Ipv4Addr, MAX_ADDRESSES>::new();
+        let mut last_error = Error::Other("No NTP servers configured");
+        for name in self.servers.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            trace!("Resolving {name}");
+            match dns_socket.query(name, DnsQueryType::A).await {
+                Ok(results) => {
+                    trace!("DNS result for {name}: {results:?}");
+                    for result in results {
+                        let IpAddress::Ipv4(ip) = result else {
+                            continue;
+                        };
+                        if !addresses.contains(&ip) {
+                            let _ = addresses.push(ip);
+                        }
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to resolve NTP server {name}: {e:?}");
+                    last_error = e.into();
+                },
+            }
         }
-        trace!("DNS result: {:?}", addresses);
 
-        let IpAddress::Ipv4(ip) = addresses[0] else {
-            return Err(Error::Other("Ipv6 address not supported"));
-        };
-
-        Ok(Self::new(stack, ip))
+        if addresses.is_empty() {
+            return Err(last_error);
+        }
+        debug!("NTP servers: {addresses:?}");
+        self.addresses = addresses;
+        self.next = 0;
+        self.resolved_at = Some(Instant::now());
+        Ok(())
     }
 
+    /// Asks each server in turn until one answers, starting after the one asked last time so the
+    /// requests are spread across them. If none answer the names are resolved again next time
     pub async fn ntp_request<'b>(&mut self, system_time: &'b mut SystemTime, use_offset: bool) -> Result<(u64, i64), Error> {
-        // TODO: add timeout
+        let stale = self.resolved_at.is_none_or(|at| at.elapsed() >= RESOLVE_INTERVAL);
+        if stale || self.addresses.is_empty() {
+            if let Err(e) = self.resolve().await {
+                if self.addresses.is_empty() {
+                    return Err(e);
+                }
+                warn!("Using previous NTP server addresses");
+            }
+        }
+
+        let mut last_error = Error::Timeout;
+        for _ in 0..self.addresses.len() {
+            let ip = self.addresses[self.next % self.addresses.len()];
+            self.next = (self.next + 1) % self.addresses.len();
+
+            debug!("NTP request to {ip}");
+            match self.request(SocketAddrV4::new(ip, NTP_PORT), system_time, use_offset).await {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    warn!("NTP server {ip} failed: {e:?}");
+                    last_error = e;
+                },
+            }
+        }
 
+        self.resolved_at = None;
+        Err(last_error)
+    }
+
+    async fn request<'b>(&mut self, server: SocketAddrV4, system_time: &'b mut SystemTime, use_offset: bool) -> Result<(u64, i64), Error> {
         let mut socket = UdpSocket::new(self.stack.stack(), &mut self.rx_meta, &mut self.rx_buffer, &mut self.tx_meta, &mut self.tx_buffer);
         // 0 picks a random port
         socket.bind(0)?;
@@ -126,11 +194,11 @@ impl Client {
         let timestamp_gen = TimestampGen { system_time, seconds: 0, sub_seconds: 0 };
         let context = NtpContext::new(timestamp_gen);
 
-        let r = get_time(
-            self.ntp_socket.clone().into(), 
+        let r = with_timeout(REQUEST_TIMEOUT, get_time(
+            server.into(), 
             &socket, 
             context,
-        ).await?;
+        )).await.map_err(|_| Error::Timeout)??;
 
         trace!("NtpResult: {:?}", r);
 
@@ -200,6 +268,9 @@ pub enum Error {
     /// Response was too large
     ResponseTooLarge,
 
+    /// No response within the timeout
+    Timeout,
+
     /// Error within DNS system
     Dns(DnsError),
 
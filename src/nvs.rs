@@ -7,9 +7,71 @@ use crate::partitions::NVS_PARTITION;
 pub const MAGIC: u32 = 0xdeadbeef;
 pub const MIN_OFFSET: u32 = size_of_val(&MAGIC) as u32;
 
+/// Flash is erased a sector at a time, so every write rewrites the whole sector it's in
+pub const SECTOR_SIZE: u32 = 0x1000;
+
+/// Offset of the calibrated travel record of the first blind. Wifi credentials occupy the space
+/// below 0x100
+pub const HEIGHT_OFFSET: u32 = 0x110;
+/// Distance between the records of consecutive blinds
+pub const BLIND_STRIDE: u32 = 0x20;
+/// Number of blinds with space reserved for their records
+pub const MAX_BLINDS: usize = 4;
+
+/// Offset of the absolute position record of the first blind. Positions are saved after most moves
+/// so they have the second sector to themselves, a power cut while one is saved can lose the
+/// positions but not the credentials or settings in the first
+pub const POSITION_OFFSET: u32 = SECTOR_SIZE;
+const _: () = assert!(POSITION_OFFSET + MAX_BLINDS as u32 * BLIND_STRIDE <= 2 * SECTOR_SIZE);
+
+pub const fn position_offset(blind: usize) -> u32 {
+    POSITION_OFFSET + blind as u32 * BLIND_STRIDE
+}
+
+pub const fn height_offset(blind: usize) -> u32 {
+    HEIGHT_OFFSET + blind as u32 * BLIND_STRIDE
+}
+
+/// Offset of the schedule rules of the first blind, after the space reserved for positions
+pub const SCHEDULE_OFFSET: u32 = 0x200;
+/// Distance between the schedules of consecutive blinds
+pub const SCHEDULE_STRIDE: u32 = 0x100;
+
+pub const fn schedule_offset(blind: usize) -> u32 {
+    SCHEDULE_OFFSET + blind as u32 * SCHEDULE_STRIDE
+}
+
+/// Offset of the glare setting of the first blind, after the space reserved for schedules
+pub const GLARE_OFFSET: u32 = 0x600;
+/// Distance between the glare settings of consecutive blinds
+pub const GLARE_STRIDE: u32 = 0x10;
+
+pub const fn glare_offset(blind: usize) -> u32 {
+    GLARE_OFFSET + blind as u32 * GLARE_STRIDE
+}
+
+/// Offset of the vacation setting, shared by all the blinds
+pub const VACATION_OFFSET: u32 = 0x640;
+
+/// Offset of the one-shot timers of all the blinds
+pub const TIMERS_OFFSET: u32 = 0x660;
+
+/// Offset of the scenes shared by all the blinds
+pub const SCENES_OFFSET: u32 = 0x700;
+
+/// Offset of the time zone, after the space reserved for scenes
+pub const TIME_ZONE_OFFSET: u32 = 0x800;
+
+const CRC_ALGO: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+const CRC_LEN: u32 = size_of::<u32>() as u32;
+/// Longest record, checksum included, that [`Nvs::write_record`] can write
+pub const MAX_RECORD_LEN: usize = 0x100;
+
 #[derive(Debug)]
 pub enum Error {
     OutOfBounds,
+    /// Longer than [`MAX_RECORD_LEN`]
+    RecordTooLarge,
     Storage(FlashStorageError),
 }
 
@@ -79,4 +141,26 @@ impl<'a> Nvs<'a> {
         self.flash.write(self.offset + offset, buf)?;
         Ok(())
     }
+
+    /// Reads a record written by [`write_record`][Self::write_record] into `buf`. Returns false if
+    /// the checksum doesn't match, which is also the case for erased flash
+    pub fn read_record(&mut self, offset: u32, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut crc = [0; CRC_LEN as usize];
+        self.read(offset, &mut crc)?;
+        self.read(offset + CRC_LEN, buf)?;
+
+        Ok(u32::from_le_bytes(crc) == CRC_ALGO.checksum(buf))
+    }
+
+    /// Writes `buf` prefixed with it's checksum so it can be validated when read back. Both go in a
+    /// single write so the sector is only erased once. The sector is read, erased and written back
+    /// so losing power part way through can corrupt any record in it, not just this one
+    pub fn write_record(&mut self, offset: u32, buf: &[u8]) -> Result<(), Error> {
+        let mut record = [0; MAX_RECORD_LEN];
+        let record = record.get_mut(..CRC_LEN as usize + buf.len()).ok_or(Error::RecordTooLarge)?;
+        let (crc, data) = record.split_at_mut(CRC_LEN as usize);
+        crc.copy_from_slice(&CRC_ALGO.checksum(buf).to_le_bytes());
+        data.copy_from_slice(buf);
+        self.write(offset, record)
+    }
 }
\ No newline at end of file
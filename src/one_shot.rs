@@ -0,0 +1,254 @@
+//! Timers that move a blind once, either after a delay or at the next occurrence of a time of day
+//!
+//! Due times are unix timestamps so a timer keeps its place across a reboot. Timers are written as
+//! `<when>,<position>` in URLs, where `when` is `+<minutes>` or `HH:MM` and `position` is a percentage
+//! closed or `raise`/`lower`. `+20,lower` lowers the blind in 20 minutes and `07:15,raise` raises it
+//! at quarter past seven, tomorrow if that's already gone today.
+
+use core::{fmt, str::FromStr};
+
+use heapless::Vec;
+
+pub const MAX_TIMERS: usize = 8;
+/// Length of an encoded timer
+pub const TIMER_LEN: usize = 10;
+/// Length of an encoded timer list, a count followed by the timers
+pub const TIMERS_LEN: usize = 1 + MAX_TIMERS * TIMER_LEN;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// Longest delay, a week
+const MAX_DELAY_MINUTES: u32 = 7 * 24 * 60;
+
+/// Pending timers, soonest first
+pub type Timers = Vec<OneShot, MAX_TIMERS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum When {
+    /// Minutes from now
+    In(u32),
+    /// Minutes since local midnight, today if it's still to come or else tomorrow
+    At(u16),
+}
+
+impl When {
+    /// Due time as a unix timestamp, given the current time and the local UTC offset in seconds at
+    /// any time, so a time of day the other side of a daylight saving change is still local time
+    pub fn resolve(self, now: i64, offset_at: impl Fn(i64) -> i32) -> i64 {
+        match self {
+            Self::In(minutes) => now + minutes as i64 * 60,
+            Self::At(minute) => {
+                let offset = offset_at(now) as i64;
+                let local = now + offset;
+                let midnight = local - local.rem_euclid(SECONDS_PER_DAY);
+                // The offset in force at a local time is looked up at roughly when it is in UTC
+                let to_utc = |local: i64| local - offset_at(local - offset) as i64;
+                let due = to_utc(midnight + minute as i64 * 60);
+                if due > now { due } else { to_utc(midnight + SECONDS_PER_DAY + minute as i64 * 60) }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OneShot {
+    /// Unix timestamp
+    pub due: i64,
+    pub blind: u8,
+    /// Target position in percent closed, 0 is raised and 100 lowered
+    pub position: u8,
+}
+
+impl OneShot {
+    pub fn encode(&self) -> [u8; TIMER_LEN] {
+        let due = self.due.to_le_bytes();
+        [due[0], due[1], due[2], due[3], due[4], due[5], due[6], due[7], self.blind, self.position]
+    }
+
+    pub fn decode(buf: &[u8; TIMER_LEN]) -> Option<Self> {
+        let mut due = [0; 8];
+        due.copy_from_slice(&buf[..8]);
+        let timer = Self { due: i64::from_le_bytes(due), blind: buf[8], position: buf[9] };
+        (timer.position <= 100).then_some(timer)
+    }
+}
+
+/// Adds `timer` keeping the soonest first, returning it back if there's no room
+pub fn add(timers: &mut Timers, timer: OneShot) -> Result<(), OneShot> {
+    let index = timers.iter().position(|t| t.due > timer.due).unwrap_or(timers.len());
+    timers.insert(index, timer)
+}
+
+/// Removes and returns the timers due at or before `now`, soonest first
+pub fn take_due(timers: &mut Timers, now: i64) -> Timers {
+    let count = timers.iter().take_while(|timer| timer.due <= now).count();
+    let due = timers[..count].iter().copied().collect();
+    timers.rotate_left(count);
+    timers.truncate(timers.len() - count);
+    due
+}
+
+pub fn encode_timers(timers: &[OneShot]) -> [u8; TIMERS_LEN] {
+    let mut buf = [0; TIMERS_LEN];
+    let count = timers.len().min(MAX_TIMERS);
+    buf[0] = count as u8;
+    for (timer, chunk) in timers[..count].iter().zip(buf[1..].chunks_exact_mut(TIMER_LEN)) {
+        chunk.copy_from_slice(&timer.encode());
+    }
+    buf
+}
+
+/// Decodes timers written by [`encode_timers`], skipping any that are invalid
+pub fn decode_timers(buf: &[u8; TIMERS_LEN]) -> Timers {
+    let count = (buf[0] as usize).min(MAX_TIMERS);
+    let mut timers = Timers::new();
+    for timer in buf[1..].chunks_exact(TIMER_LEN).take(count).filter_map(|chunk| OneShot::decode(chunk.try_into().ok()?)) {
+        let _ = add(&mut timers, timer);
+    }
+    timers
+}
+
+/// The text form of a timer before its due time is worked out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerRequest {
+    pub when: When,
+    pub position: u8,
+}
+
+/// Problems with the text form of a timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseTimerError {
+    /// Expected `+<minutes>`, up to a week, or `HH:MM`
+    When,
+    /// Expected 0-100, `raise` or `lower`
+    Position,
+    MissingField,
+}
+
+impl FromStr for When {
+    type Err = ParseTimerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(minutes) = s.strip_prefix('+') {
+            let minutes: u32 = minutes.parse().map_err(|_| ParseTimerError::When)?;
+            if minutes > MAX_DELAY_MINUTES {
+                return Err(ParseTimerError::When);
+            }
+            return Ok(Self::In(minutes));
+        }
+
+        let (hours, minutes) = s.split_once(':').ok_or(ParseTimerError::When)?;
+        let hours: u16 = hours.parse().map_err(|_| ParseTimerError::When)?;
+        let minutes: u16 = minutes.parse().map_err(|_| ParseTimerError::When)?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(ParseTimerError::When);
+        }
+        Ok(Self::At(hours * 60 + minutes))
+    }
+}
+
+impl FromStr for TimerRequest {
+    type Err = ParseTimerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (when, position) = s.split_once(',').ok_or(ParseTimerError::MissingField)?;
+        let position = match position {
+            "raise" => 0,
+            "lower" => 100,
+            position => position.parse().ok().filter(|&p| p <= 100).ok_or(ParseTimerError::Position)?,
+        };
+
+        Ok(Self { when: when.parse()?, position })
+    }
+}
+
+impl fmt::Display for When {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::In(minutes) => write!(f, "+{minutes}"),
+            Self::At(minute) => write!(f, "{:02}:{:02}", minute / 60, minute % 60),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-06-21 12:00:00 UTC
+    const NOON: i64 = 1_718_971_200;
+
+    fn timer(due: i64) -> OneShot {
+        OneShot { due, blind: 0, position: 100 }
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(When::In(20).resolve(NOON, |_| 0), NOON + 20 * 60);
+
+        // Later today
+        assert_eq!(When::At(13 * 60).resolve(NOON, |_| 0), NOON + 60 * 60);
+        // Already gone, so tomorrow
+        assert_eq!(When::At(7 * 60 + 15).resolve(NOON, |_| 0), NOON + 19 * 60 * 60 + 15 * 60);
+        assert_eq!(When::At(12 * 60).resolve(NOON, |_| 0), NOON + SECONDS_PER_DAY);
+        // 13:00 local is noon UTC, so tomorrow
+        assert_eq!(When::At(13 * 60).resolve(NOON, |_| 3600), NOON + SECONDS_PER_DAY);
+        // 02:00 on the 22nd locally
+        assert_eq!(When::At(3 * 60).resolve(NOON, |_| 14 * 3600), NOON + 60 * 60);
+        // 08:00 on the 21st locally
+        assert_eq!(When::At(9 * 60).resolve(NOON, |_| -4 * 3600), NOON + 60 * 60);
+    }
+
+    #[test]
+    fn resolve_across_daylight_saving() {
+        // The UK goes from GMT to BST at 01:00 UTC on 2024-03-31 and back at 01:00 UTC on 2024-10-27
+        let spring = |time| if time >= 1_711_846_800 { 3600 } else { 0 };
+        let autumn = |time| if time >= 1_729_990_800 { 0 } else { 3600 };
+
+        // 07:15 the morning after is 06:15 UTC in BST and 07:15 UTC in GMT
+        assert_eq!(When::At(7 * 60 + 15).resolve(1_711_828_800, spring), 1_711_865_700);
+        assert_eq!(When::At(7 * 60 + 15).resolve(1_729_972_800, autumn), 1_730_013_300);
+        // Delays aren't affected
+        assert_eq!(When::In(12 * 60).resolve(1_711_828_800, spring), 1_711_828_800 + 12 * 60 * 60);
+    }
+
+    #[test]
+    fn soonest_first() {
+        let mut timers = Timers::new();
+        for due in [30, 10, 20, 10] {
+            add(&mut timers, timer(due)).unwrap();
+        }
+        assert!(timers.iter().map(|t| t.due).eq([10, 10, 20, 30]));
+
+        assert_eq!(take_due(&mut timers, 5), Timers::new());
+        assert!(take_due(&mut timers, 20).iter().map(|t| t.due).eq([10, 10, 20]));
+        assert!(timers.iter().map(|t| t.due).eq([30]));
+    }
+
+    #[test]
+    fn full() {
+        let mut timers = Timers::new();
+        for due in 0..MAX_TIMERS as i64 {
+            add(&mut timers, timer(due)).unwrap();
+        }
+        assert_eq!(add(&mut timers, timer(0)), Err(timer(0)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut timers = Timers::new();
+        add(&mut timers, OneShot { due: NOON, blind: 1, position: 0 }).unwrap();
+        add(&mut timers, OneShot { due: -1, blind: 2, position: 55 }).unwrap();
+        assert_eq!(decode_timers(&encode_timers(&timers)), timers);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("+20,lower".parse(), Ok(TimerRequest { when: When::In(20), position: 100 }));
+        assert_eq!("07:15,raise".parse(), Ok(TimerRequest { when: When::At(7 * 60 + 15), position: 0 }));
+        assert_eq!("23:59,40".parse(), Ok(TimerRequest { when: When::At(23 * 60 + 59), position: 40 }));
+        assert_eq!("24:00,40".parse::<TimerRequest>(), Err(ParseTimerError::When));
+        assert_eq!("+10081,40".parse::<TimerRequest>(), Err(ParseTimerError::When));
+        assert_eq!("+5,101".parse::<TimerRequest>(), Err(ParseTimerError::Position));
+        assert_eq!("+5".parse::<TimerRequest>(), Err(ParseTimerError::MissingField));
+    }
+}
@@ -0,0 +1,136 @@
+//! Encoding of step pin toggles into RMT pulse codes
+//!
+//! Each RMT pulse code is a u32 holding two (level, duration) halves. A half with a duration of 0
+//! marks the end of the transmission. Kept free of any peripheral types so it can be checked on the
+//! host.
+
+/// Largest duration that fits in one half of a pulse code
+pub const MAX_DURATION: u32 = 0x7fff;
+
+/// Builds a pulse code in the layout the RMT peripheral expects
+pub const fn pulse_code(level1: bool, length1: u16, level2: bool, length2: u16) -> u32 {
+    (length1 as u32 & MAX_DURATION)
+        | ((level1 as u32) << 15)
+        | ((length2 as u32 & MAX_DURATION) << 16)
+        | ((level2 as u32) << 31)
+}
+
+/// Result of [`encode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoded {
+    /// Number of intervals that were encoded
+    pub intervals: usize,
+    /// Number of codes written, including the end marker
+    pub codes: usize,
+    /// Level of the interval after the last one encoded, to pass as `level` when encoding the rest
+    pub next_level: bool,
+}
+
+/// Encodes as many of `intervals` as fit into `codes`, followed by an end marker.
+///
+/// Each interval (in microseconds) is the time spent at one level after a toggle of the step pin,
+/// starting at `level` and alternating from there, so two intervals make one step pulse. Intervals
+/// too long for a single half are split across several halves at the same level. If even the first
+/// interval doesn't fit it's truncated so the caller always makes progress.
+pub fn encode(intervals: &[u32], ticks_per_us: u32, level: bool, codes: &mut [u32]) -> Encoded {
+    if codes.is_empty() {
+        return Encoded { intervals: 0, codes: 0, next_level: level };
+    }
+
+    // The end marker can share the last code so everything but one half is available
+    let capacity = codes.len() * 2 - 1;
+    let mut halves = 0;
+    let mut consumed = 0;
+
+    codes.fill(0);
+
+    for (i, &interval) in intervals.iter().enumerate() {
+        let level = level ^ (i % 2 == 1);
+        let mut ticks = (interval as u64 * ticks_per_us as u64).max(1);
+
+        let needed = ticks.div_ceil(MAX_DURATION as u64) as usize;
+        if halves + needed > capacity {
+            if consumed > 0 || halves == capacity {
+                break;
+            }
+            ticks = ((capacity - halves) as u64) * MAX_DURATION as u64;
+        }
+
+        while ticks > 0 {
+            let length = ticks.min(MAX_DURATION as u64);
+            set_half(codes, halves, level, length as u16);
+            halves += 1;
+            ticks -= length;
+        }
+        consumed += 1;
+    }
+
+    // The remaining half is already zeroed which makes it the end marker
+    Encoded { intervals: consumed, codes: halves / 2 + 1, next_level: level ^ (consumed % 2 == 1) }
+}
+
+fn set_half(codes: &mut [u32], half: usize, level: bool, length: u16) {
+    let code = &mut codes[half / 2];
+    if half % 2 == 0 {
+        *code = (*code & 0xffff_0000) | pulse_code(level, length, false, 0);
+    } else {
+        *code = (*code & 0x0000_ffff) | pulse_code(false, 0, level, length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u16 = MAX_DURATION as u16;
+
+    #[test]
+    fn layout() {
+        assert_eq!(pulse_code(true, 10, false, 20), 10 | 1 << 15 | 20 << 16);
+        assert_eq!(pulse_code(false, MAX, true, 1), 0x7fff | 1 << 16 | 1 << 31);
+    }
+
+    #[test]
+    fn steps() {
+        let mut codes = [0xffff_ffff; 4];
+        let encoded = encode(&[10, 20, 30, 40], 2, true, &mut codes);
+        assert_eq!(encoded, Encoded { intervals: 4, codes: 3, next_level: true });
+        assert_eq!(codes[..3], [pulse_code(true, 20, false, 40), pulse_code(true, 60, false, 80), 0]);
+    }
+
+    #[test]
+    fn split() {
+        let mut codes = [0; 4];
+        let encoded = encode(&[70_000, 10], 1, true, &mut codes);
+        assert_eq!(encoded, Encoded { intervals: 2, codes: 3, next_level: true });
+        assert_eq!(codes[..3], [pulse_code(true, MAX, true, MAX), pulse_code(true, 4466, false, 10), 0]);
+    }
+
+    #[test]
+    fn partial_batch_keeps_polarity() {
+        // Room for 3 halves, the second interval needs 2 more on top of the 2 of the first
+        let mut codes = [0; 2];
+        let intervals = [40_000, 40_000, 10];
+        let encoded = encode(&intervals, 1, true, &mut codes);
+        assert_eq!(encoded, Encoded { intervals: 1, codes: 2, next_level: false });
+        assert_eq!(codes, [pulse_code(true, MAX, true, 7233), 0]);
+
+        // The rest carries on low rather than starting high again
+        let encoded = encode(&intervals[1..], 1, encoded.next_level, &mut codes);
+        assert_eq!(encoded, Encoded { intervals: 2, codes: 2, next_level: false });
+        assert_eq!(codes, [pulse_code(false, MAX, false, 7233), pulse_code(true, 10, false, 0)]);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut codes = [0; 2];
+        let encoded = encode(&[200_000, 10], 1, true, &mut codes);
+        assert_eq!(encoded, Encoded { intervals: 1, codes: 2, next_level: false });
+        assert_eq!(codes, [pulse_code(true, MAX, true, MAX), pulse_code(true, MAX, false, 0)]);
+    }
+
+    #[test]
+    fn no_room() {
+        assert_eq!(encode(&[10], 1, true, &mut []), Encoded { intervals: 0, codes: 0, next_level: true });
+    }
+}
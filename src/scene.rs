@@ -0,0 +1,247 @@
+//! Named sets of positions for one or more blinds
+//!
+//! Scenes are written as `<name>=<positions>` where `positions` is a single percentage for every
+//! blind, such as `privacy=70`, or one per blind with `-` for blinds the scene leaves alone, such as
+//! `evening=70,-,100`. Scenes live in fixed slots so schedule rules can refer to them by slot.
+
+use core::{fmt, str::FromStr};
+
+use heapless::String;
+
+pub const MAX_SCENES: usize = 8;
+pub const MAX_NAME_LEN: usize = 15;
+/// Blinds a scene has positions for
+pub const MAX_SCENE_BLINDS: usize = 4;
+/// Length of an encoded scene, the length of the name, the name padded to full length, then the
+/// positions
+pub const SCENE_LEN: usize = 1 + MAX_NAME_LEN + MAX_SCENE_BLINDS;
+/// Length of the encoded scene slots
+pub const SCENES_LEN: usize = MAX_SCENES * SCENE_LEN;
+/// Longest text form of a scene, the longest name with a three digit position for every blind
+pub const MAX_SCENE_TEXT_LEN: usize = MAX_NAME_LEN + "=".len() + MAX_SCENE_BLINDS * "100,".len() - 1;
+
+/// Stored in place of the position of a blind the scene leaves alone
+const NO_POSITION: u8 = 0xff;
+
+pub type Name = String<MAX_NAME_LEN>;
+/// Scene slots, `None` where empty
+pub type Scenes = [Option<Scene>; MAX_SCENES];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scene {
+    pub name: Name,
+    /// Position in percent closed of each blind, `None` for blinds the scene leaves alone
+    pub positions: [Option<u8>; MAX_SCENE_BLINDS],
+}
+
+impl Scene {
+    pub fn encode(&self) -> [u8; SCENE_LEN] {
+        let mut buf = [0; SCENE_LEN];
+        buf[0] = self.name.len() as u8;
+        buf[1..1 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        for (byte, position) in buf[1 + MAX_NAME_LEN..].iter_mut().zip(self.positions) {
+            *byte = position.unwrap_or(NO_POSITION);
+        }
+        buf
+    }
+
+    /// `None` for an empty slot
+    pub fn decode(buf: &[u8; SCENE_LEN]) -> Option<Self> {
+        let len = buf[0] as usize;
+        if len == 0 || len > MAX_NAME_LEN {
+            return None;
+        }
+        let name = core::str::from_utf8(&buf[1..1 + len]).ok().filter(|name| valid_name(name))?;
+
+        let mut positions = [None; MAX_SCENE_BLINDS];
+        for (position, &byte) in positions.iter_mut().zip(&buf[1 + MAX_NAME_LEN..]) {
+            *position = (byte <= 100).then_some(byte);
+        }
+
+        Some(Self { name: name.try_into().ok()?, positions })
+    }
+}
+
+/// Names start with a letter so they can't be mistaken for a position, and only use characters
+/// that don't need escaping in a URL
+pub fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Slot of the scene called `name`
+pub fn find(scenes: &Scenes, name: &str) -> Option<usize> {
+    scenes.iter().position(|scene| scene.as_ref().is_some_and(|scene| scene.name == name))
+}
+
+/// Replaces the scene with the same name, or else puts it in the first empty slot. Returns the
+/// slot, or the scene back if there's no room
+pub fn set(scenes: &mut Scenes, scene: Scene) -> Result<usize, Scene> {
+    let Some(slot) = find(scenes, &scene.name).or_else(|| scenes.iter().position(Option::is_none)) else {
+        return Err(scene);
+    };
+    scenes[slot] = Some(scene);
+    Ok(slot)
+}
+
+/// Position of `blind` in each slot, `None` where the slot is empty or the scene leaves it alone
+pub fn positions_of(scenes: &Scenes, blind: usize) -> [Option<u8>; MAX_SCENES] {
+    scenes.each_ref().map(|scene| scene.as_ref()?.positions.get(blind).copied().flatten())
+}
+
+pub fn encode_scenes(scenes: &Scenes) -> [u8; SCENES_LEN] {
+    let mut buf = [0; SCENES_LEN];
+    for (scene, chunk) in scenes.iter().zip(buf.chunks_exact_mut(SCENE_LEN)) {
+        if let Some(scene) = scene {
+            chunk.copy_from_slice(&scene.encode());
+        }
+    }
+    buf
+}
+
+/// Decodes scenes written by [`encode_scenes`], emptying any slots that are invalid
+pub fn decode_scenes(buf: &[u8; SCENES_LEN]) -> Scenes {
+    let mut scenes = [const { None }; MAX_SCENES];
+    for (scene, chunk) in scenes.iter_mut().zip(buf.chunks_exact(SCENE_LEN)) {
+        *scene = chunk.try_into().ok().and_then(Scene::decode);
+    }
+    scenes
+}
+
+/// Problems with the text form of a scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseSceneError {
+    /// Expected up to 15 letters, digits, `-` or `_`, starting with a letter
+    Name,
+    /// Expected 0-100 or `-`
+    Position,
+    /// More positions than blinds
+    TooManyBlinds,
+    MissingField,
+}
+
+impl FromStr for Scene {
+    type Err = ParseSceneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, positions) = s.split_once('=').ok_or(ParseSceneError::MissingField)?;
+        if !valid_name(name) {
+            return Err(ParseSceneError::Name);
+        }
+
+        let parse = |position: &str| match position {
+            "-" => Ok(None),
+            position => position.parse().ok().filter(|&p| p <= 100).map(Some).ok_or(ParseSceneError::Position),
+        };
+
+        let positions = if positions.contains(',') {
+            let mut all = [None; MAX_SCENE_BLINDS];
+            let mut fields = positions.split(',');
+            for (position, field) in all.iter_mut().zip(&mut fields) {
+                *position = parse(field)?;
+            }
+            if fields.next().is_some() {
+                return Err(ParseSceneError::TooManyBlinds);
+            }
+            all
+        } else {
+            [parse(positions)?; MAX_SCENE_BLINDS]
+        };
+
+        Ok(Self { name: name.try_into().map_err(|()| ParseSceneError::Name)?, positions })
+    }
+}
+
+impl fmt::Display for Scene {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.name)?;
+        if let [Some(first), rest @ ..] = self.positions {
+            if rest.iter().all(|&position| position == Some(first)) {
+                return write!(f, "{first}");
+            }
+        }
+
+        // Trailing blinds the scene leaves alone are implied
+        let len = self.positions.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+        for (i, position) in self.positions[..len.max(1)].iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            match position {
+                Some(position) => write!(f, "{separator}{position}")?,
+                None => write!(f, "{separator}-")?,
+            }
+        }
+        // A single position would be read back as applying to every blind
+        if len <= 1 {
+            write!(f, ",-")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(s: &str) -> Scene {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(scene("privacy=70").positions, [Some(70); MAX_SCENE_BLINDS]);
+        assert_eq!(scene("evening=70,-,100").positions, [Some(70), None, Some(100), None]);
+        assert_eq!(scene("one=70,-").positions, [Some(70), None, None, None]);
+        assert_eq!("1st=70".parse::<Scene>(), Err(ParseSceneError::Name));
+        assert_eq!("a b=70".parse::<Scene>(), Err(ParseSceneError::Name));
+        assert_eq!("waytoolongforaname=70".parse::<Scene>(), Err(ParseSceneError::Name));
+        assert_eq!("movie=101".parse::<Scene>(), Err(ParseSceneError::Position));
+        assert_eq!("movie=1,2,3,4,5".parse::<Scene>(), Err(ParseSceneError::TooManyBlinds));
+        assert_eq!("movie".parse::<Scene>(), Err(ParseSceneError::MissingField));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in ["privacy=70", "evening=70,-,100", "one=70,-", "two=-,30", "none=-,-"] {
+            let displayed = scene(s).to_string();
+            assert_eq!(scene(&displayed), scene(s), "{s} displayed as {displayed}");
+        }
+        assert_eq!(scene("privacy=70").to_string(), "privacy=70");
+        assert_eq!(scene("evening=70,-,100").to_string(), "evening=70,-,100");
+
+        let longest = scene("abcdefghijklmno=100,100,100,99").to_string();
+        assert!(longest.len() <= MAX_SCENE_TEXT_LEN, "{longest}");
+    }
+
+    #[test]
+    fn slots() {
+        let mut scenes: Scenes = [const { None }; MAX_SCENES];
+        assert_eq!(set(&mut scenes, scene("privacy=70")), Ok(0));
+        assert_eq!(set(&mut scenes, scene("evening=70,-,100")), Ok(1));
+        assert_eq!(set(&mut scenes, scene("privacy=60")), Ok(0));
+        assert_eq!(find(&scenes, "evening"), Some(1));
+        assert_eq!(find(&scenes, "movie"), None);
+
+        assert_eq!(positions_of(&scenes, 1)[..3], [Some(60), None, None]);
+        assert_eq!(positions_of(&scenes, 2)[..3], [Some(60), Some(100), None]);
+        assert_eq!(positions_of(&scenes, MAX_SCENE_BLINDS), [None; MAX_SCENES]);
+
+        scenes[0] = None;
+        assert_eq!(set(&mut scenes, scene("movie=100")), Ok(0));
+        for i in 2..MAX_SCENES {
+            let mut name = Name::new();
+            core::fmt::Write::write_fmt(&mut name, format_args!("s{i}")).unwrap();
+            set(&mut scenes, Scene { name, positions: [None; MAX_SCENE_BLINDS] }).unwrap();
+        }
+        assert!(set(&mut scenes, scene("full=1")).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut scenes: Scenes = [const { None }; MAX_SCENES];
+        scenes[0] = Some(scene("privacy=70"));
+        scenes[3] = Some(scene("evening=70,-,100"));
+        assert_eq!(decode_scenes(&encode_scenes(&scenes)), scenes);
+        assert_eq!(decode_scenes(&[0xff; SCENES_LEN]), [const { None }; MAX_SCENES]);
+    }
+}
@@ -0,0 +1,1086 @@
+//! Rules deciding where the blind should be through the day
+//!
+//! Each rule fires once a day on the weekdays it's enabled for, at a fixed time or relative to
+//! sunrise or sunset, optionally clamped to a window. The desired position at any moment is the
+//! target of whichever rule fired most recently. Times are minutes since local midnight.
+//!
+//! Rules have a compact text form so they can be written in a URL, for example
+//! `MTWTF--,sunset+30,100,latest=22:00` lowers the blind 30 minutes after sunset on weekdays but no
+//! later than 22:00.
+//!
+//! While away, [`Vacation`] mode moves each rule by a random but repeatable amount every day.
+//!
+//! A blind can also be lowered to shade a window while the sun shines into it, see [`Glare`].
+//!
+//! Instead of a position a rule can move the blind to its position in a [`Scene`], written by name
+//! as in `-----SS,09:00,morning`.
+//!
+//! A rule can also move the blind gently, a little at a time over a number of minutes ending when
+//! it fires, so `MTWTF--,07:00,0,over=20` opens the blind over the 20 minutes before 07:00.
+
+use core::{fmt, str::FromStr};
+
+use heapless::Vec;
+
+use crate::scene::{self, Scenes};
+
+pub const MAX_RULES: usize = 16;
+/// Length of an encoded rule
+pub const RULE_LEN: usize = 9;
+/// Length of an encoded rule list, a count followed by the rules
+pub const RULES_LEN: usize = 1 + MAX_RULES * RULE_LEN;
+/// Longest text form of a rule, moving to a scene with the longest name with every option set
+pub const MAX_RULE_TEXT_LEN: usize = WEEKDAYS.len()
+    + ",sunrise-32768,".len()
+    + scene::MAX_NAME_LEN
+    + ",earliest=00:00,latest=00:00,over=255".len();
+
+const MINUTES_PER_DAY: i32 = 24 * 60;
+const WEEKDAYS: &[u8; 7] = b"MTWTFSS";
+/// Every day of the week
+pub const ALL_DAYS: u8 = 0x7f;
+/// Stored in place of a missing clamp
+const NO_CLAMP: u16 = 0xffff;
+/// Set in a stored target to mark it as a scene slot rather than a position
+const SCENE_TARGET: u8 = 0x80;
+/// Bits of the stored trigger kind, the rest of the byte holds the ramp
+const TRIGGER_KIND_MASK: u8 = 0x03;
+/// Longest gentle move in minutes
+pub const MAX_RAMP: u8 = 60;
+
+pub type Rules = Vec<Rule, MAX_RULES>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Minutes since midnight
+    Time(u16),
+    /// Minutes after sunrise, negative for before
+    Sunrise(i16),
+    /// Minutes after sunset, negative for before
+    Sunset(i16),
+}
+
+/// Where a rule moves the blind to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Percent closed, 0 is raised and 100 lowered
+    Position(u8),
+    /// The position of the blind in the scene in this slot
+    Scene(u8),
+}
+
+impl Target {
+    /// The position, given the blind's position in each scene slot. `None` if the scene is gone or
+    /// leaves the blind alone
+    pub fn resolve(self, scenes: &[Option<u8>]) -> Option<u8> {
+        match self {
+            Self::Position(position) => Some(position),
+            Self::Scene(slot) => scenes.get(slot as usize).copied().flatten(),
+        }
+    }
+
+    fn encode(self) -> u8 {
+        match self {
+            Self::Position(position) => position,
+            Self::Scene(slot) => SCENE_TARGET | slot,
+        }
+    }
+
+    fn decode(byte: u8) -> Self {
+        if byte & SCENE_TARGET != 0 && ((byte & !SCENE_TARGET) as usize) < scene::MAX_SCENES {
+            Self::Scene(byte & !SCENE_TARGET)
+        } else {
+            Self::Position(byte.min(100))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    /// Bit 0 is Monday
+    pub weekdays: u8,
+    pub trigger: Trigger,
+    /// The rule never fires before this time
+    pub earliest: Option<u16>,
+    /// The rule never fires after this time
+    pub latest: Option<u16>,
+    pub target: Target,
+    /// Minutes to spread the move over, ending when the rule fires. 0 moves straight away
+    pub ramp: u8,
+}
+
+/// What rules need to know about a day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Day {
+    /// Julian day number, which picks the day's [`Vacation`] offsets
+    pub number: i32,
+    /// 0 is Monday
+    pub weekday: u8,
+    /// `None` when the sun doesn't rise or set
+    pub sunrise: Option<u16>,
+    pub sunset: Option<u16>,
+}
+
+impl Rule {
+    /// Time the rule fires on `day`, if it does
+    pub fn fires_at(&self, day: &Day) -> Option<u16> {
+        if self.weekdays & (1 << day.weekday) == 0 {
+            return None;
+        }
+
+        let minute = match self.trigger {
+            Trigger::Time(minute) => minute as i32,
+            Trigger::Sunrise(offset) => day.sunrise? as i32 + offset as i32,
+            Trigger::Sunset(offset) => day.sunset? as i32 + offset as i32,
+        };
+        Some(self.clamp(minute.clamp(0, MINUTES_PER_DAY - 1) as u16))
+    }
+
+    /// `minute` kept between `earliest` and `latest`
+    fn clamp(&self, mut minute: u16) -> u16 {
+        if let Some(earliest) = self.earliest {
+            minute = minute.max(earliest);
+        }
+        if let Some(latest) = self.latest {
+            minute = minute.min(latest);
+        }
+        minute
+    }
+
+    pub fn encode(&self) -> [u8; RULE_LEN] {
+        let (kind, value) = match self.trigger {
+            Trigger::Time(minute) => (0, minute),
+            Trigger::Sunrise(offset) => (1, offset as u16),
+            Trigger::Sunset(offset) => (2, offset as u16),
+        };
+        let value = value.to_le_bytes();
+        let earliest = self.earliest.unwrap_or(NO_CLAMP).to_le_bytes();
+        let latest = self.latest.unwrap_or(NO_CLAMP).to_le_bytes();
+
+        [
+            self.weekdays,
+            kind | self.ramp.min(MAX_RAMP) << 2,
+            value[0],
+            value[1],
+            earliest[0],
+            earliest[1],
+            latest[0],
+            latest[1],
+            self.target.encode(),
+        ]
+    }
+
+    pub fn decode(buf: &[u8; RULE_LEN]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let clamp = |i: usize| Some(u16_at(i)).filter(|&v| v != NO_CLAMP);
+
+        let trigger = match buf[1] & TRIGGER_KIND_MASK {
+            0 => Trigger::Time(u16_at(2)),
+            1 => Trigger::Sunrise(u16_at(2) as i16),
+            2 => Trigger::Sunset(u16_at(2) as i16),
+            _ => return None,
+        };
+
+        Some(Self { weekdays: buf[0] & ALL_DAYS, trigger, earliest: clamp(4), latest: clamp(6), target: Target::decode(buf[8]), ramp: (buf[1] >> 2).min(MAX_RAMP) })
+    }
+}
+
+/// Length of an encoded vacation setting
+pub const VACATION_LEN: usize = 6;
+/// Largest offset vacation mode can apply, in minutes either way
+pub const MAX_JITTER: u16 = 180;
+
+/// Moves every rule earlier or later by up to `max_jitter` minutes, by a different amount each day,
+/// so the blinds don't move at exactly the same times day after day. The offsets only depend on
+/// the seed, the day and the rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vacation {
+    pub seed: u32,
+    pub max_jitter: u16,
+}
+
+/// Spreads the bits of `x` over the whole word, from the lowbias32 hash
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+impl Vacation {
+    /// The same setting with the offsets of another blind so they don't all move together
+    pub fn for_blind(self, blind: usize) -> Self {
+        Self { seed: mix(self.seed ^ blind as u32), ..self }
+    }
+
+    /// Minutes rule number `rule` moves by on `day`, within +/- `max_jitter`
+    pub fn offset(&self, day: i32, rule: usize) -> i16 {
+        let max = self.max_jitter.min(MAX_JITTER) as u32;
+        let hash = mix(self.seed ^ mix(day as u32 ^ mix(rule as u32)));
+        (hash % (2 * max + 1)) as i16 - max as i16
+    }
+
+    /// `minute` moved by the offset of rule number `rule` on `day`, staying on the same day
+    pub fn apply(&self, minute: u16, day: i32, rule: usize) -> u16 {
+        (minute as i32 + self.offset(day, rule) as i32).clamp(0, MINUTES_PER_DAY - 1) as u16
+    }
+
+    pub fn encode(&self) -> [u8; VACATION_LEN] {
+        let seed = self.seed.to_le_bytes();
+        let max_jitter = self.max_jitter.to_le_bytes();
+        [seed[0], seed[1], seed[2], seed[3], max_jitter[0], max_jitter[1]]
+    }
+
+    pub fn decode(buf: &[u8; VACATION_LEN]) -> Option<Self> {
+        let seed = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let max_jitter = u16::from_le_bytes([buf[4], buf[5]]);
+        (max_jitter <= MAX_JITTER).then_some(Self { seed, max_jitter })
+    }
+}
+
+/// A rule firing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub position: u8,
+    /// Minutes since the rule fired
+    pub age: u32,
+}
+
+/// When each rule that fires on `day` does so, as `(minute, index, rule, position)`. With
+/// `vacation` the rules fire at their jittered times, still within their `earliest` and `latest`.
+/// Rules for scenes that leave the blind alone don't fire
+fn firings<'a>(
+    rules: &'a [Rule],
+    day: &'a Day,
+    vacation: Option<&'a Vacation>,
+    scenes: &'a [Option<u8>],
+) -> impl Iterator<Item = (u16, usize, &'a Rule, u8)> + 'a {
+    rules.iter().enumerate().filter_map(move |(index, rule)| {
+        let position = rule.target.resolve(scenes)?;
+        let at = rule.fires_at(day)?;
+        let at = vacation.map_or(at, |vacation| rule.clamp(vacation.apply(at, day.number, index)));
+        Some((at, index, rule, position))
+    })
+}
+
+/// The rule that fired most recently at or before `minute` on `days[0]`. `days` continues
+/// backwards, `days[1]` being yesterday and so on. Of rules firing at the same time the last one
+/// wins. `scenes` is the blind's position in each scene slot
+pub fn last_event(
+    rules: &[Rule],
+    days: &[Day],
+    minute: u16,
+    vacation: Option<&Vacation>,
+    scenes: &[Option<u8>],
+) -> Option<Event> {
+    days.iter().enumerate().find_map(|(i, day)| {
+        firings(rules, day, vacation, scenes)
+            .map(|(at, _, _, position)| (at, position))
+            .filter(|&(at, _)| i > 0 || at <= minute)
+            .fold(None, |latest: Option<(u16, u8)>, (at, position)| match latest {
+                Some((latest_at, _)) if latest_at > at => latest,
+                _ => Some((at, position)),
+            })
+            .map(|(at, position)| Event { position, age: (i as i32 * MINUTES_PER_DAY + minute as i32 - at as i32) as u32 })
+    })
+}
+
+/// Where the blind should be part way through the gentle move of a rule firing later on `day`,
+/// starting from `from`. `None` when no gentle move is under way. Gentle moves don't start before
+/// midnight
+pub fn ramp_position(
+    rules: &[Rule],
+    day: &Day,
+    minute: u16,
+    vacation: Option<&Vacation>,
+    scenes: &[Option<u8>],
+    from: u8,
+) -> Option<u8> {
+    let (at, ramp, to) = firings(rules, day, vacation, scenes)
+        .filter(|&(at, _, rule, _)| rule.ramp > 0 && at > minute && at.saturating_sub(rule.ramp as u16) <= minute)
+        .min_by_key(|&(at, index, _, _)| (at, index))
+        .map(|(at, _, rule, to)| (at, rule.ramp.min(at.min(u8::MAX as u16) as u8), to))?;
+
+    // Minutes into the ramp, so the first step is taken as soon as it starts
+    let done = (minute + ramp as u16 + 1 - at) as i32;
+    Some((from as i32 + (to as i32 - from as i32) * done / ramp as i32) as u8)
+}
+
+/// A rule that's going to fire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upcoming {
+    /// Index into the days passed to [`upcoming`], 0 is today
+    pub day: usize,
+    pub minute: u16,
+    pub trigger: Trigger,
+    pub target: Target,
+    pub position: u8,
+    pub ramp: u8,
+}
+
+/// The next `N` rules to fire after `minute` on `days[0]`, in order. Unlike [`last_event`] `days`
+/// goes forwards, `days[1]` being tomorrow and so on
+pub fn upcoming<const N: usize>(
+    rules: &[Rule],
+    days: &[Day],
+    minute: u16,
+    vacation: Option<&Vacation>,
+    scenes: &[Option<u8>],
+) -> Vec<Upcoming, N> {
+    let mut upcoming = Vec::new();
+    for (i, day) in days.iter().enumerate() {
+        let mut fired: Vec<(u16, usize, &Rule, u8), MAX_RULES> =
+            firings(rules, day, vacation, scenes).filter(|&(at, _, _, _)| i > 0 || at > minute).collect();
+        fired.sort_unstable_by_key(|&(at, index, _, _)| (at, index));
+
+        for (at, _, rule, position) in fired {
+            let event = Upcoming { day: i, minute: at, trigger: rule.trigger, target: rule.target, position, ramp: rule.ramp };
+            if upcoming.push(event).is_err() {
+                return upcoming;
+            }
+        }
+    }
+    upcoming
+}
+
+/// The position of the rule that fired most recently, see [`last_event`]
+pub fn desired_position(
+    rules: &[Rule],
+    days: &[Day],
+    minute: u16,
+    vacation: Option<&Vacation>,
+    scenes: &[Option<u8>],
+) -> Option<u8> {
+    last_event(rules, days, minute, vacation, scenes).map(|event| event.position)
+}
+
+/// The schedule of a blind held off after someone moved it. Times are Unix timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hold {
+    /// When the command that started the hold was sent
+    pub since: i64,
+    /// When the hold ends, `None` to hold until the schedule next changes
+    pub until: Option<i64>,
+}
+
+/// Why a [`Hold`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldEnd {
+    /// A rule fired after the hold started
+    Fired,
+    /// A gentle move started or glare control started or stopped, see [`Activity::changes`]
+    Changed,
+    /// The end time was reached
+    Expired,
+}
+
+impl Hold {
+    /// Why the hold is over at `now`, if it is. `fired_at` is when the rule that fired most recently
+    /// did so and `changed` whether the schedule's [`Activity`] changed since it was last checked. A
+    /// hold with an end time ignores the schedule and lasts until then
+    pub fn end(&self, now: i64, fired_at: Option<i64>, changed: bool) -> Option<HoldEnd> {
+        match self.until {
+            Some(until) if now >= until => Some(HoldEnd::Expired),
+            Some(_) => None,
+            None if fired_at.is_some_and(|fired_at| fired_at > self.since) => Some(HoldEnd::Fired),
+            None if changed => Some(HoldEnd::Changed),
+            None => None,
+        }
+    }
+}
+
+/// What besides the rule that fired most recently is deciding where the blind should be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activity {
+    /// A gentle move is under way
+    pub ramping: bool,
+    /// Glare control sees the sun shining in
+    pub shading: bool,
+}
+
+impl Activity {
+    /// Whether going from `self` to `next` changes the schedule: a gentle move starting, or glare
+    /// control starting or stopping. The steps of a gentle move under way don't, nor does it ending
+    /// as its rule fires
+    pub fn changes(self, next: Self) -> bool {
+        (next.ramping && !self.ramping) || next.shading != self.shading
+    }
+}
+
+pub fn encode_rules(rules: &[Rule]) -> [u8; RULES_LEN] {
+    let mut buf = [0; RULES_LEN];
+    let count = rules.len().min(MAX_RULES);
+    buf[0] = count as u8;
+    for (rule, chunk) in rules[..count].iter().zip(buf[1..].chunks_exact_mut(RULE_LEN)) {
+        chunk.copy_from_slice(&rule.encode());
+    }
+    buf
+}
+
+/// Decodes rules written by [`encode_rules`], skipping any that are invalid
+pub fn decode_rules(buf: &[u8; RULES_LEN]) -> Rules {
+    let count = (buf[0] as usize).min(MAX_RULES);
+    buf[1..]
+        .chunks_exact(RULE_LEN)
+        .take(count)
+        .filter_map(|chunk| Rule::decode(chunk.try_into().ok()?))
+        .collect()
+}
+
+/// Length of an encoded glare setting
+pub const GLARE_LEN: usize = 6;
+
+/// Lowers the blind at least to `position` while the sun is within the cone of sky the window looks
+/// out on, and high enough to get over anything in front of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Glare {
+    /// Direction the window faces in degrees clockwise from north
+    pub azimuth: u16,
+    /// Width in degrees of the cone of sky the sun shines in from, centred on `azimuth`
+    pub field_of_view: u16,
+    /// Degrees above the horizon the sun has to be to shine in
+    pub min_elevation: u8,
+    /// Shading position in percent closed
+    pub position: u8,
+}
+
+impl Glare {
+    /// Whether the sun at `azimuth` and `elevation` (both in degrees) shines in the window
+    pub fn in_sun(&self, azimuth: f64, elevation: f64) -> bool {
+        let mut from_centre = (azimuth - self.azimuth as f64) % 360.;
+        if from_centre > 180. {
+            from_centre -= 360.;
+        } else if from_centre < -180. {
+            from_centre += 360.;
+        }
+
+        elevation >= self.min_elevation as f64 && from_centre.abs() <= self.field_of_view as f64 / 2.
+    }
+
+    /// Position to move to when the rules want `desired`, never raising the blind above the
+    /// shading position while the sun shines in
+    pub fn shade(&self, desired: Option<u8>, azimuth: f64, elevation: f64) -> Option<u8> {
+        if self.in_sun(azimuth, elevation) {
+            Some(desired.map_or(self.position, |desired| desired.max(self.position)))
+        } else {
+            desired
+        }
+    }
+
+    pub fn encode(&self) -> [u8; GLARE_LEN] {
+        let azimuth = self.azimuth.to_le_bytes();
+        let field_of_view = self.field_of_view.to_le_bytes();
+        [azimuth[0], azimuth[1], field_of_view[0], field_of_view[1], self.min_elevation, self.position]
+    }
+
+    pub fn decode(buf: &[u8; GLARE_LEN]) -> Option<Self> {
+        let glare = Self {
+            azimuth: u16::from_le_bytes([buf[0], buf[1]]),
+            field_of_view: u16::from_le_bytes([buf[2], buf[3]]),
+            min_elevation: buf[4],
+            position: buf[5],
+        };
+        glare.is_valid().then_some(glare)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.azimuth < 360 && self.field_of_view <= 360 && self.min_elevation <= 90 && self.position <= 100
+    }
+}
+
+/// Problems with the text form of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseRuleError {
+    /// Expected 7 characters, `-` for days the rule is disabled on
+    Weekdays,
+    Trigger,
+    /// Expected HH:MM
+    Time,
+    /// Expected 0-100 or the name of a scene
+    Position,
+    /// Expected `earliest=HH:MM`, `latest=HH:MM` (no earlier than `earliest`) or `over=<minutes>` up
+    /// to an hour
+    Clamp,
+    /// Expected an azimuth of 0-359, a field of view of 0-360 or an elevation of 0-90 degrees
+    Angle,
+    MissingField,
+}
+
+fn parse_time(s: &str) -> Result<u16, ParseRuleError> {
+    let (hours, minutes) = s.split_once(':').ok_or(ParseRuleError::Time)?;
+    let hours: u16 = hours.parse().map_err(|_| ParseRuleError::Time)?;
+    let minutes: u16 = minutes.parse().map_err(|_| ParseRuleError::Time)?;
+    if hours >= 24 || minutes >= 60 {
+        return Err(ParseRuleError::Time);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+fn parse_offset(s: &str) -> Result<i16, ParseRuleError> {
+    if s.is_empty() {
+        return Ok(0);
+    }
+    let offset = s.strip_prefix('+').unwrap_or(s);
+    let offset: i16 = offset.parse().map_err(|_| ParseRuleError::Trigger)?;
+    if offset.unsigned_abs() as i32 >= MINUTES_PER_DAY {
+        return Err(ParseRuleError::Trigger);
+    }
+    Ok(offset)
+}
+
+impl FromStr for Trigger {
+    type Err = ParseRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(offset) = s.strip_prefix("sunrise") {
+            Ok(Self::Sunrise(parse_offset(offset)?))
+        } else if let Some(offset) = s.strip_prefix("sunset") {
+            Ok(Self::Sunset(parse_offset(offset)?))
+        } else {
+            Ok(Self::Time(parse_time(s)?))
+        }
+    }
+}
+
+impl Rule {
+    /// Parses the text form of a rule, looking up the names of scenes in `scenes`
+    pub fn parse(s: &str, scenes: &Scenes) -> Result<Self, ParseRuleError> {
+        let mut fields = s.split(',');
+        let mut next = || fields.next().ok_or(ParseRuleError::MissingField);
+
+        let days = next()?.as_bytes();
+        if days.len() != WEEKDAYS.len() {
+            return Err(ParseRuleError::Weekdays);
+        }
+        let weekdays = days.iter().enumerate().filter(|(_, &c)| c != b'-').fold(0, |mask, (i, _)| mask | 1 << i);
+
+        let trigger = next()?.parse()?;
+
+        let target = match next()? {
+            name if scene::valid_name(name) => {
+                Target::Scene(scene::find(scenes, name).ok_or(ParseRuleError::Position)? as u8)
+            },
+            position => match position.parse() {
+                Ok(position) if position <= 100 => Target::Position(position),
+                _ => return Err(ParseRuleError::Position),
+            },
+        };
+
+        let mut rule = Rule { weekdays, trigger, earliest: None, latest: None, target, ramp: 0 };
+        for clamp in fields {
+            match clamp.split_once('=') {
+                Some(("earliest", time)) => rule.earliest = Some(parse_time(time)?),
+                Some(("latest", time)) => rule.latest = Some(parse_time(time)?),
+                Some(("over", minutes)) => {
+                    rule.ramp = minutes.parse().ok().filter(|&m| m <= MAX_RAMP).ok_or(ParseRuleError::Clamp)?;
+                },
+                _ => return Err(ParseRuleError::Clamp),
+            }
+        }
+        if let (Some(earliest), Some(latest)) = (rule.earliest, rule.latest) {
+            if earliest > latest {
+                return Err(ParseRuleError::Clamp);
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// The text form of a glare setting is `<azimuth>,<field of view>,<min elevation>,<position>`, so
+/// `225,120,10,80` lowers the blind to 80% while the sun is above 10 degrees and within 60 degrees
+/// of south west
+impl FromStr for Glare {
+    type Err = ParseRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',');
+        let mut next = || fields.next().ok_or(ParseRuleError::MissingField);
+        let mut angle = || next()?.parse::<u16>().map_err(|_| ParseRuleError::Angle);
+
+        let azimuth = angle()?;
+        let field_of_view = angle()?;
+        let min_elevation = angle()?.try_into().map_err(|_| ParseRuleError::Angle)?;
+        let position = next()?.parse().map_err(|_| ParseRuleError::Position)?;
+
+        let glare = Glare { azimuth, field_of_view, min_elevation, position };
+        if position > 100 {
+            Err(ParseRuleError::Position)
+        } else if !glare.is_valid() {
+            Err(ParseRuleError::Angle)
+        } else {
+            Ok(glare)
+        }
+    }
+}
+
+impl fmt::Display for Glare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.azimuth, self.field_of_view, self.min_elevation, self.position)
+    }
+}
+
+struct Minutes(u16);
+
+impl fmt::Display for Minutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Time(minute) => write!(f, "{}", Minutes(minute)),
+            Self::Sunrise(0) => write!(f, "sunrise"),
+            Self::Sunrise(offset) => write!(f, "sunrise{offset:+}"),
+            Self::Sunset(0) => write!(f, "sunset"),
+            Self::Sunset(offset) => write!(f, "sunset{offset:+}"),
+        }
+    }
+}
+
+/// The text form of a rule, see [`Rule::display`]
+pub struct RuleText<'a> {
+    rule: &'a Rule,
+    scenes: &'a Scenes,
+}
+
+impl Rule {
+    /// The text form of the rule, with names for the scenes in `scenes`
+    pub fn display<'a>(&'a self, scenes: &'a Scenes) -> RuleText<'a> {
+        RuleText { rule: self, scenes }
+    }
+}
+
+impl fmt::Display for RuleText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = self.rule;
+        for (i, &c) in WEEKDAYS.iter().enumerate() {
+            let c = if rule.weekdays & (1 << i) != 0 { c } else { b'-' };
+            write!(f, "{}", c as char)?;
+        }
+        write!(f, ",{},", rule.trigger)?;
+        match rule.target {
+            Target::Position(position) => write!(f, "{position}")?,
+            Target::Scene(slot) => match self.scenes.get(slot as usize) {
+                Some(Some(scene)) => write!(f, "{}", scene.name)?,
+                _ => write!(f, "?")?,
+            },
+        }
+        if let Some(earliest) = rule.earliest {
+            write!(f, ",earliest={}", Minutes(earliest))?;
+        }
+        if let Some(latest) = rule.latest {
+            write!(f, ",latest={}", Minutes(latest))?;
+        }
+        if rule.ramp > 0 {
+            write!(f, ",over={}", rule.ramp)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VACATION: Vacation = Vacation { seed: 0x1234_5678, max_jitter: 30 };
+
+    fn day(number: i32) -> Day {
+        Day { number, weekday: (number % 7) as u8, sunrise: Some(6 * 60), sunset: Some(20 * 60) }
+    }
+
+    const NO_SCENES: [Option<u8>; scene::MAX_SCENES] = [None; scene::MAX_SCENES];
+
+    fn rule(minute: u16, position: u8) -> Rule {
+        Rule { weekdays: ALL_DAYS, trigger: Trigger::Time(minute), earliest: None, latest: None, target: Target::Position(position), ramp: 0 }
+    }
+
+    #[test]
+    fn longest_display() {
+        let mut scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        scenes[0] = Some("abcdefghijklmno=100".parse().unwrap());
+        let rule = Rule {
+            weekdays: ALL_DAYS,
+            trigger: Trigger::Sunrise(i16::MIN),
+            earliest: Some(23 * 60 + 59),
+            latest: Some(23 * 60 + 59),
+            target: Target::Scene(0),
+            ramp: u8::MAX,
+        };
+        assert_eq!(rule.display(&scenes).to_string().len(), MAX_RULE_TEXT_LEN);
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        for text in [
+            "MTWTF--,sunset+30,100,latest=22:00",
+            "-----SS,09:00,0",
+            "M------,sunrise-15,40,earliest=06:30,latest=08:00",
+            "MTWTFSS,sunset,100,earliest=19:00",
+            "-------,00:00,0",
+            "MTWTFSS,23:59,55",
+        ] {
+            let rule = Rule::parse(text, &scenes).unwrap();
+            assert_eq!(rule.display(&scenes).to_string(), text);
+        }
+
+        let rule = Rule::parse("MTWTF--,sunset+30,100,latest=22:00", &scenes).unwrap();
+        assert_eq!(
+            rule,
+            Rule { weekdays: 0x1f, trigger: Trigger::Sunset(30), earliest: None, latest: Some(22 * 60), target: Target::Position(100), ramp: 0 }
+        );
+        // Any character other than '-' enables the day and a zero offset is dropped
+        let rule = Rule::parse("xxxxxxx,sunrise+0,0", &scenes).unwrap();
+        assert_eq!(rule.weekdays, ALL_DAYS);
+        assert_eq!(rule.display(&scenes).to_string(), "MTWTFSS,sunrise,0");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        let parse = |text| Rule::parse(text, &scenes);
+
+        assert_eq!(parse("MTWTF,08:00,0"), Err(ParseRuleError::Weekdays));
+        assert_eq!(parse("MTWTFSS,24:00,0"), Err(ParseRuleError::Time));
+        assert_eq!(parse("MTWTFSS,8,0"), Err(ParseRuleError::Time));
+        assert_eq!(parse("MTWTFSS,sunset+1440,0"), Err(ParseRuleError::Trigger));
+        assert_eq!(parse("MTWTFSS,sunrise+x,0"), Err(ParseRuleError::Trigger));
+        assert_eq!(parse("MTWTFSS,08:00"), Err(ParseRuleError::MissingField));
+        assert_eq!(parse("MTWTFSS,08:00,0,until=09:00"), Err(ParseRuleError::Clamp));
+        assert_eq!(parse("MTWTFSS,08:00,0,latest=9:60"), Err(ParseRuleError::Time));
+        assert_eq!(parse("MTWTFSS,sunset,100,earliest=22:00,latest=21:00"), Err(ParseRuleError::Clamp));
+        assert!(parse("MTWTFSS,sunset,100,earliest=21:00,latest=21:00").is_ok());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        let rules: Rules = ["MTWTF--,sunset+30,100,latest=22:00", "-----SS,sunrise-45,0,earliest=07:00", "MTWTFSS,12:15,50"]
+            .iter()
+            .map(|text| Rule::parse(text, &scenes).unwrap())
+            .collect();
+
+        for rule in &rules {
+            assert_eq!(Rule::decode(&rule.encode()), Some(*rule));
+        }
+        assert_eq!(decode_rules(&encode_rules(&rules)), rules);
+        assert_eq!(decode_rules(&encode_rules(&[])), Rules::new());
+        // Erased flash holds no rules rather than garbage
+        assert_eq!(decode_rules(&[0xff; RULES_LEN]), Rules::new());
+
+        let mut invalid = rules[0].encode();
+        invalid[1] = 3;
+        assert_eq!(Rule::decode(&invalid), None);
+    }
+
+    #[test]
+    fn day_rollover() {
+        let day = day(2_460_000);
+        // Offsets that would cross midnight stay on the day
+        let late = Rule { trigger: Trigger::Sunset(5 * 60), ..rule(0, 100) };
+        let early = Rule { trigger: Trigger::Sunrise(-7 * 60), ..rule(0, 0) };
+        assert_eq!(late.fires_at(&day), Some(MINUTES_PER_DAY as u16 - 1));
+        assert_eq!(early.fires_at(&day), Some(0));
+        assert_eq!(Rule { latest: Some(22 * 60), ..late }.fires_at(&day), Some(22 * 60));
+        assert_eq!(Rule { earliest: Some(60), ..early }.fires_at(&day), Some(60));
+        assert_eq!(late.fires_at(&Day { sunset: None, ..day }), None);
+        assert_eq!(Rule { weekdays: ALL_DAYS & !(1 << day.weekday), ..late }.fires_at(&day), None);
+
+        // Yesterday's last rule holds until today's first fires
+        let rules = [late, Rule { trigger: Trigger::Time(8 * 60), ..rule(0, 0) }];
+        let days = [day, self::day(2_459_999)];
+        assert_eq!(desired_position(&rules, &days, 0, None, &NO_SCENES), Some(100));
+        assert_eq!(
+            last_event(&rules, &days, 7 * 60, None, &NO_SCENES),
+            Some(Event { position: 100, age: 7 * 60 + 1 })
+        );
+        assert_eq!(desired_position(&rules, &days, 8 * 60, None, &NO_SCENES), Some(0));
+        assert_eq!(desired_position(&rules, &days, MINUTES_PER_DAY as u16 - 1, None, &NO_SCENES), Some(100));
+
+        // A rule that only fires on another weekday is found further back
+        let weekly = [Rule { weekdays: 1 << days[1].weekday, ..rule(12 * 60, 30) }];
+        let week: Vec<Day, 8> = (0..8).map(|i| self::day(2_460_000 - i)).collect();
+        assert_eq!(desired_position(&weekly, &week[..1], 13 * 60, None, &NO_SCENES), None);
+        assert_eq!(last_event(&weekly, &week, 13 * 60, None, &NO_SCENES), Some(Event { position: 30, age: MINUTES_PER_DAY as u32 + 60 }));
+        let earlier = [Rule { weekdays: 1 << week[6].weekday, ..weekly[0] }];
+        assert_eq!(last_event(&earlier, &week, 13 * 60, None, &NO_SCENES).unwrap().age, 6 * MINUTES_PER_DAY as u32 + 60);
+    }
+
+    #[test]
+    fn vacation_offsets_are_bounded() {
+        for day in 0..100 {
+            for rule in 0..MAX_RULES {
+                assert!(VACATION.offset(day, rule).unsigned_abs() <= VACATION.max_jitter);
+            }
+        }
+
+        let wide = Vacation { max_jitter: u16::MAX, ..VACATION };
+        assert!((0..100).all(|day| wide.offset(day, 0).unsigned_abs() <= MAX_JITTER));
+
+        let none = Vacation { max_jitter: 0, ..VACATION };
+        assert!((0..100).all(|day| none.offset(day, 0) == 0));
+    }
+
+    #[test]
+    fn vacation_offsets_are_deterministic() {
+        let offsets = |vacation: Vacation| (0..20).map(move |day| vacation.offset(2_460_000 + day, 1));
+
+        assert!(offsets(VACATION).eq(offsets(VACATION)));
+        assert!(!offsets(VACATION).eq(offsets(Vacation { seed: 0x8765_4321, ..VACATION })));
+        assert!(!offsets(VACATION).eq(offsets(VACATION.for_blind(1))));
+        // Varies from day to day
+        assert!(offsets(VACATION).any(|offset| offset != VACATION.offset(2_460_000, 1)));
+    }
+
+    #[test]
+    fn vacation_stays_on_the_day() {
+        let vacation = Vacation { max_jitter: MAX_JITTER, ..VACATION };
+        for day in 0..100 {
+            assert!(vacation.apply(0, day, 0) < MINUTES_PER_DAY as u16);
+            assert!(vacation.apply(MINUTES_PER_DAY as u16 - 1, day, 0) < MINUTES_PER_DAY as u16);
+        }
+    }
+
+    #[test]
+    fn desired_position_with_vacation() {
+        let rules = [rule(8 * 60, 0), rule(21 * 60, 100)];
+        let days = [day(2_460_001), day(2_460_000)];
+
+        assert_eq!(desired_position(&rules, &days, 8 * 60, None, &NO_SCENES), Some(0));
+
+        let raise = VACATION.apply(8 * 60, days[0].number, 0);
+        assert_eq!(desired_position(&rules, &days, raise - 1, Some(&VACATION), &NO_SCENES), Some(100));
+        assert_eq!(desired_position(&rules, &days, raise, Some(&VACATION), &NO_SCENES), Some(0));
+    }
+
+    #[test]
+    fn vacation_keeps_clamps() {
+        let vacation = Vacation { max_jitter: MAX_JITTER, ..VACATION };
+        let late = Rule { trigger: Trigger::Sunset(0), earliest: Some(19 * 60 + 30), latest: Some(20 * 60 + 30), ..rule(0, 100) };
+        let rules = [late];
+
+        let times: Vec<u16, 50> = (0..50)
+            .map(|number| upcoming::<1>(&rules, &[day(2_460_000 + number)], 0, Some(&vacation), &NO_SCENES)[0].minute)
+            .collect();
+        assert!(times.iter().all(|&minute| (19 * 60 + 30..=20 * 60 + 30).contains(&minute)), "{times:?}");
+        // Jittered right up to both clamps over enough days
+        assert!(times.contains(&(19 * 60 + 30)) && times.contains(&(20 * 60 + 30)));
+        assert!(times.iter().any(|&minute| minute != 20 * 60));
+    }
+
+    #[test]
+    fn last_event_age() {
+        let rules = [rule(8 * 60, 0), rule(21 * 60, 100)];
+        let days = [day(2_460_001), day(2_460_000)];
+
+        assert_eq!(last_event(&rules, &days, 8 * 60, None, &NO_SCENES), Some(Event { position: 0, age: 0 }));
+        assert_eq!(last_event(&rules, &days, 12 * 60, None, &NO_SCENES), Some(Event { position: 0, age: 4 * 60 }));
+        // Fired yesterday evening
+        assert_eq!(last_event(&rules, &days, 7 * 60, None, &NO_SCENES), Some(Event { position: 100, age: 10 * 60 }));
+        assert_eq!(last_event(&rules, &days[..1], 7 * 60, None, &NO_SCENES), None);
+    }
+
+    #[test]
+    fn upcoming_across_days() {
+        let rules = [
+            rule(21 * 60, 100),
+            rule(8 * 60, 0),
+            Rule { weekdays: 1, trigger: Trigger::Sunset(-10), earliest: None, latest: None, target: Target::Position(50), ramp: 0 },
+        ];
+        // Sunday then Monday
+        let days = [day(2_460_002), day(2_460_003)];
+        assert_eq!(days.map(|day| day.weekday), [6, 0]);
+
+        let events: Vec<Upcoming, 3> = upcoming(&rules, &days, 8 * 60, None, &NO_SCENES);
+        assert_eq!(
+            events,
+            [
+                Upcoming { day: 0, minute: 21 * 60, trigger: Trigger::Time(21 * 60), target: Target::Position(100), position: 100, ramp: 0 },
+                Upcoming { day: 1, minute: 8 * 60, trigger: Trigger::Time(8 * 60), target: Target::Position(0), position: 0, ramp: 0 },
+                Upcoming { day: 1, minute: 20 * 60 - 10, trigger: Trigger::Sunset(-10), target: Target::Position(50), position: 50, ramp: 0 },
+            ]
+        );
+
+        let events: Vec<Upcoming, 16> = upcoming(&rules, &days, 22 * 60, None, &NO_SCENES);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].day, 1);
+
+        let raise = VACATION.apply(8 * 60, days[1].number, 1);
+        let events: Vec<Upcoming, 1> = upcoming(&rules, &days[1..], 0, Some(&VACATION), &NO_SCENES);
+        assert_eq!(events[0].minute, raise);
+    }
+
+    #[test]
+    fn scene_targets() {
+        let mut scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        scene::set(&mut scenes, "privacy=70".parse().unwrap()).unwrap();
+        scene::set(&mut scenes, "evening=-,100".parse().unwrap()).unwrap();
+
+        let morning = Rule::parse("MTWTFSS,08:00,privacy", &scenes).unwrap();
+        let evening = Rule::parse("MTWTFSS,sunset,evening,latest=22:00", &scenes).unwrap();
+        assert_eq!(morning.target, Target::Scene(0));
+        assert_eq!(evening.target, Target::Scene(1));
+        assert_eq!(Rule::parse("MTWTFSS,08:00,movie", &scenes), Err(ParseRuleError::Position));
+        assert_eq!(Rule::parse("MTWTFSS,08:00,101", &scenes), Err(ParseRuleError::Position));
+
+        assert_eq!(evening.display(&scenes).to_string(), "MTWTFSS,sunset,evening,latest=22:00");
+        assert_eq!(Rule::decode(&evening.encode()), Some(evening));
+        // Positions stored before scenes existed still decode
+        let mut old = rule(60, 100).encode();
+        old[8] = 0xfe;
+        assert_eq!(Rule::decode(&old).unwrap().target, Target::Position(100));
+
+        let rules = [morning, evening];
+        let days = [day(2_460_002)];
+        assert_eq!(desired_position(&rules, &days, 9 * 60, None, &scene::positions_of(&scenes, 0)), Some(70));
+        // The evening scene leaves blind 0 alone
+        assert_eq!(desired_position(&rules, &days, 23 * 60, None, &scene::positions_of(&scenes, 0)), Some(70));
+        assert_eq!(desired_position(&rules, &days, 23 * 60, None, &scene::positions_of(&scenes, 1)), Some(100));
+        assert_eq!(desired_position(&rules, &days, 23 * 60, None, &NO_SCENES), None);
+    }
+
+    #[test]
+    fn gentle() {
+        let scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        let wake = Rule::parse("MTWTFSS,07:00,0,over=20", &scenes).unwrap();
+        assert_eq!(wake.ramp, 20);
+        assert_eq!(wake.display(&scenes).to_string(), "MTWTFSS,07:00,0,over=20");
+        assert_eq!(Rule::decode(&wake.encode()), Some(wake));
+        assert_eq!(Rule::parse("MTWTFSS,07:00,0,over=61", &scenes), Err(ParseRuleError::Clamp));
+
+        let rules = [rule(21 * 60, 100), wake];
+        let day = day(2_460_002);
+        let ramp = |minute| ramp_position(&rules, &day, minute, None, &NO_SCENES, 100);
+
+        assert_eq!(ramp(6 * 60 + 39), None);
+        assert_eq!(ramp(6 * 60 + 40), Some(95));
+        assert_eq!(ramp(6 * 60 + 50), Some(45));
+        assert_eq!(ramp(6 * 60 + 59), Some(0));
+        // The rule itself has fired
+        assert_eq!(ramp(7 * 60), None);
+        assert_eq!(desired_position(&rules, &[day], 7 * 60, None, &NO_SCENES), Some(0));
+
+        // Starts no earlier than midnight
+        let early = [Rule { trigger: Trigger::Time(10), ..wake }];
+        assert_eq!(ramp_position(&early, &day, 0, None, &NO_SCENES, 100), Some(90));
+        assert_eq!(ramp_position(&early, &day, 9, None, &NO_SCENES, 100), Some(0));
+    }
+
+    #[test]
+    fn hold_until_the_schedule_changes() {
+        let hold = Hold { since: 1_000, until: None };
+
+        assert_eq!(hold.end(1_060, None, false), None);
+        assert_eq!(hold.end(1_060, Some(940), false), None);
+        // A rule firing in the same minute as the command doesn't end it
+        assert_eq!(hold.end(1_060, Some(1_000), false), None);
+        assert_eq!(hold.end(1_060, Some(1_020), false), Some(HoldEnd::Fired));
+        assert_eq!(hold.end(1_060, Some(940), true), Some(HoldEnd::Changed));
+        assert_eq!(hold.end(i64::MAX, None, false), None);
+    }
+
+    #[test]
+    fn hold_for_a_fixed_time() {
+        let hold = Hold { since: 1_000, until: Some(1_000 + 30 * 60) };
+
+        assert_eq!(hold.end(1_060, Some(1_020), true), None);
+        assert_eq!(hold.end(1_000 + 30 * 60 - 1, Some(1_020), false), None);
+        assert_eq!(hold.end(1_000 + 30 * 60, None, false), Some(HoldEnd::Expired));
+        // Resuming sets the end time in the past
+        assert_eq!(Hold { until: Some(i64::MIN), ..hold }.end(1_060, None, false), Some(HoldEnd::Expired));
+    }
+
+    #[test]
+    fn hold_during_a_gentle_move() {
+        let scenes: Scenes = [const { None }; scene::MAX_SCENES];
+        let rules = [rule(21 * 60, 100), Rule::parse("MTWTFSS,07:00,0,over=20", &scenes).unwrap()];
+        let days = [day(2_460_002), day(2_460_001)];
+        let quiet = Activity { ramping: false, shading: false };
+
+        // Runs the schedule a minute at a time from `from` with a hold placed at `since`, like the
+        // schedule task does, returning when and why the hold ended
+        let run = |from: u16, since: u16| {
+            let hold = Hold { since: since as i64 * 60, until: None };
+            let mut previous = None;
+            for minute in from..MINUTES_PER_DAY as u16 {
+                let last = last_event(&rules, &days, minute, None, &NO_SCENES).unwrap();
+                let ramping = ramp_position(&rules, &days[0], minute, None, &NO_SCENES, last.position).is_some();
+                let activity = Activity { ramping, ..quiet };
+                let changed = previous.is_some_and(|previous: Activity| previous.changes(activity));
+                previous = Some(activity);
+
+                let now = minute as i64 * 60;
+                if minute >= since {
+                    if let Some(end) = hold.end(now, Some(now - last.age as i64 * 60), changed) {
+                        return Some((minute, end));
+                    }
+                }
+            }
+            None
+        };
+
+        // Moved part way through, the steps that follow leave it alone until the rule fires
+        assert_eq!(run(6 * 60 + 30, 6 * 60 + 45), Some((7 * 60, HoldEnd::Fired)));
+        assert_eq!(run(6 * 60 + 45, 6 * 60 + 45), Some((7 * 60, HoldEnd::Fired)));
+        // Moved before it, the gentle move starting takes over
+        assert_eq!(run(6 * 60, 6 * 60 + 10), Some((6 * 60 + 40, HoldEnd::Changed)));
+
+        let shading = Activity { shading: true, ..quiet };
+        assert!(quiet.changes(shading) && shading.changes(quiet));
+        assert!(!shading.changes(shading));
+        let ramping = Activity { ramping: true, ..quiet };
+        assert!(quiet.changes(ramping));
+        assert!(!ramping.changes(ramping) && !ramping.changes(quiet));
+    }
+
+    #[test]
+    fn vacation_round_trip() {
+        assert_eq!(Vacation::decode(&VACATION.encode()), Some(VACATION));
+        assert_eq!(Vacation::decode(&[0xff; VACATION_LEN]), None);
+    }
+
+    #[test]
+    fn glare() {
+        let south_west: Glare = "225,120,10,80".parse().unwrap();
+        assert_eq!(south_west, Glare { azimuth: 225, field_of_view: 120, min_elevation: 10, position: 80 });
+        assert_eq!(south_west.to_string(), "225,120,10,80");
+        assert!(south_west.in_sun(165., 20.));
+        assert!(south_west.in_sun(285., 10.));
+        assert!(!south_west.in_sun(286., 20.));
+        assert!(!south_west.in_sun(225., 9.));
+
+        // The field of view wraps around north either way
+        let north: Glare = "350,40,0,50".parse().unwrap();
+        assert!(north.in_sun(10., 5.));
+        assert!(north.in_sun(330., 5.));
+        assert!(north.in_sun(-10., 5.));
+        assert!(!north.in_sun(11., 5.));
+        assert!(!north.in_sun(329., 5.));
+        let wide: Glare = "10,360,0,50".parse().unwrap();
+        assert!(wide.in_sun(190., 0.));
+
+        // High in the sky just east of north, as at midday in a southern hemisphere summer
+        assert!(north.in_sun(5., 79.));
+        assert!(!south_west.in_sun(5., 79.));
+
+        // Shading only ever lowers the blind
+        assert_eq!(north.shade(Some(20), 0., 30.), Some(50));
+        assert_eq!(north.shade(Some(90), 0., 30.), Some(90));
+        assert_eq!(north.shade(None, 0., 30.), Some(50));
+        assert_eq!(north.shade(Some(20), 180., 30.), Some(20));
+        assert_eq!(north.shade(None, 180., 30.), None);
+
+        assert_eq!("360,90,0,50".parse::<Glare>(), Err(ParseRuleError::Angle));
+        assert_eq!("0,361,0,50".parse::<Glare>(), Err(ParseRuleError::Angle));
+        assert_eq!("0,90,91,50".parse::<Glare>(), Err(ParseRuleError::Angle));
+        assert_eq!("0,90,0,101".parse::<Glare>(), Err(ParseRuleError::Position));
+        assert_eq!("0,90,0".parse::<Glare>(), Err(ParseRuleError::MissingField));
+        assert_eq!(Glare::decode(&north.encode()), Some(north));
+        assert_eq!(Glare::decode(&[0xff; GLARE_LEN]), None);
+    }
+}
@@ -0,0 +1,362 @@
+//! Local times of sunrise, sunset and twilight
+//!
+//! Uses the NOAA approximation of the sun's position which is good to within a minute or two away
+//! from the poles. Events are found for a local date so they land on the right day whatever the UTC
+//! offset, and days where the sun never crosses the relevant altitude are reported as such rather
+//! than producing a nonsense time.
+
+use libm::{acos, asin, atan2, cos, fmod, sin, tan};
+use time::{Date, OffsetDateTime, UtcOffset};
+
+/// Julian date of the J2000 epoch
+const J2000: f64 = 2_451_545.;
+/// Julian date of the unix epoch
+const JULIAN_UNIX_EPOCH: f64 = 2_440_587.5;
+const SECONDS_PER_DAY: f64 = 86_400.;
+/// Obliquity of the ecliptic
+const OBLIQUITY: f64 = 23.4397;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    /// Degrees north
+    latitude: f64,
+    /// Degrees east
+    longitude: f64,
+}
+
+impl Coordinates {
+    /// Returns `None` if either coordinate is out of range
+    pub fn new(latitude: f64, longitude: f64) -> Option<Self> {
+        ((-90. ..=90.).contains(&latitude) && (-180. ..=180.).contains(&longitude))
+            .then_some(Self { latitude, longitude })
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolarEvent {
+    AstronomicalDawn,
+    NauticalDawn,
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+    NauticalDusk,
+    AstronomicalDusk,
+}
+
+impl SolarEvent {
+    /// Degrees the centre of the sun is below the horizon when the event happens. Sunrise and
+    /// sunset allow for refraction and the radius of the sun
+    fn depression(self) -> f64 {
+        match self {
+            Self::Sunrise | Self::Sunset => 0.833,
+            Self::CivilDawn | Self::CivilDusk => 6.,
+            Self::NauticalDawn | Self::NauticalDusk => 12.,
+            Self::AstronomicalDawn | Self::AstronomicalDusk => 18.,
+        }
+    }
+
+    fn rising(self) -> bool {
+        matches!(self, Self::AstronomicalDawn | Self::NauticalDawn | Self::CivilDawn | Self::Sunrise)
+    }
+}
+
+/// Why an event doesn't happen on a day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoEvent {
+    /// The sun stays above the event's altitude all day, such as the midnight sun
+    AlwaysAbove,
+    /// The sun stays below the event's altitude all day, such as the polar night
+    AlwaysBelow,
+}
+
+/// Time of `event` on the local `date` at `offset`
+pub fn event_time(
+    coordinates: Coordinates,
+    date: Date,
+    offset: UtcOffset,
+    event: SolarEvent,
+) -> Result<OffsetDateTime, NoEvent> {
+    let day = date.to_julian_day();
+
+    // Depending on the offset and longitude the event on the local date can belong to the solar
+    // day before or after the one with the same UTC date
+    let mut found = None;
+    for candidate in [day, day - 1, day + 1] {
+        let julian = utc_event(coordinates, candidate, event);
+        if candidate == day {
+            found = Some(julian);
+        }
+
+        if let Ok(time) = julian.map(|julian| to_offset_date_time(julian, offset)) {
+            if time.date() == date {
+                return Ok(time);
+            }
+        }
+    }
+
+    match found {
+        Some(Err(no_event)) => Err(no_event),
+        // Only near the edges of the polar day or night, where the event can skip a local date
+        _ => Err(if sun_altitude_at_noon(coordinates, day) > -event.depression() {
+            NoEvent::AlwaysAbove
+        } else {
+            NoEvent::AlwaysBelow
+        }),
+    }
+}
+
+/// All the events of the local `date` at `offset`, in the order they happen on a normal day
+pub fn events(coordinates: Coordinates, date: Date, offset: UtcOffset) -> [(SolarEvent, Result<OffsetDateTime, NoEvent>); 8] {
+    [
+        SolarEvent::AstronomicalDawn,
+        SolarEvent::NauticalDawn,
+        SolarEvent::CivilDawn,
+        SolarEvent::Sunrise,
+        SolarEvent::Sunset,
+        SolarEvent::CivilDusk,
+        SolarEvent::NauticalDusk,
+        SolarEvent::AstronomicalDusk,
+    ]
+    .map(|event| (event, event_time(coordinates, date, offset, event)))
+}
+
+/// Wraps an angle into 0-360 degrees
+fn normalize_degrees(degrees: f64) -> f64 {
+    let degrees = fmod(degrees, 360.);
+    if degrees < 0. { degrees + 360. } else { degrees }
+}
+
+fn to_radians(degrees: f64) -> f64 {
+    degrees * core::f64::consts::PI / 180.
+}
+
+fn to_degrees(radians: f64) -> f64 {
+    radians * 180. / core::f64::consts::PI
+}
+
+/// Solar transit (as a Julian date) and declination of the sun on the solar day closest to the
+/// given Julian day number
+fn transit(coordinates: Coordinates, day: i32) -> (f64, f64) {
+    let mean_noon = (day as f64 - J2000) - coordinates.longitude / 360.;
+    let anomaly = normalize_degrees(357.5291 + 0.985_600_28 * mean_noon);
+    let m = to_radians(anomaly);
+    let center = 1.9148 * sin(m) + 0.02 * sin(2. * m) + 0.0003 * sin(3. * m);
+    let ecliptic_longitude = to_radians(normalize_degrees(anomaly + center + 180. + 102.9372));
+
+    let transit = J2000 + mean_noon + 0.0053 * sin(m) - 0.0069 * sin(2. * ecliptic_longitude);
+    let declination = asin(sin(ecliptic_longitude) * sin(to_radians(OBLIQUITY)));
+
+    (transit, declination)
+}
+
+/// Julian date of `event` on the solar day closest to the Julian day number `day`
+fn utc_event(coordinates: Coordinates, day: i32, event: SolarEvent) -> Result<f64, NoEvent> {
+    let (transit, declination) = transit(coordinates, day);
+    let latitude = to_radians(coordinates.latitude);
+
+    let cos_hour_angle = (sin(to_radians(-event.depression())) - sin(latitude) * sin(declination))
+        / (cos(latitude) * cos(declination));
+    if cos_hour_angle > 1. {
+        return Err(NoEvent::AlwaysBelow);
+    }
+    if cos_hour_angle < -1. {
+        return Err(NoEvent::AlwaysAbove);
+    }
+
+    let half_day = to_degrees(acos(cos_hour_angle)) / 360.;
+    Ok(if event.rising() { transit - half_day } else { transit + half_day })
+}
+
+/// Altitude of the sun in degrees at its highest on the solar day closest to `day`
+fn sun_altitude_at_noon(coordinates: Coordinates, day: i32) -> f64 {
+    let (_, declination) = transit(coordinates, day);
+    90. - (coordinates.latitude - to_degrees(declination)).abs()
+}
+
+/// Where the sun is in the sky
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunPosition {
+    /// Degrees clockwise from north
+    pub azimuth: f64,
+    /// Degrees above the horizon, negative when the sun is below it. Doesn't allow for refraction
+    pub elevation: f64,
+}
+
+/// Position of the sun as seen from `coordinates` at `time`
+pub fn sun_position(coordinates: Coordinates, time: OffsetDateTime) -> SunPosition {
+    let days = time.unix_timestamp() as f64 / SECONDS_PER_DAY + JULIAN_UNIX_EPOCH - J2000;
+    let anomaly = normalize_degrees(357.5291 + 0.985_600_28 * days);
+    let m = to_radians(anomaly);
+    let center = 1.9148 * sin(m) + 0.02 * sin(2. * m) + 0.0003 * sin(3. * m);
+    let ecliptic_longitude = to_radians(normalize_degrees(anomaly + center + 180. + 102.9372));
+
+    let obliquity = to_radians(OBLIQUITY);
+    let declination = asin(sin(ecliptic_longitude) * sin(obliquity));
+    let right_ascension = atan2(sin(ecliptic_longitude) * cos(obliquity), cos(ecliptic_longitude));
+
+    let sidereal = to_radians(normalize_degrees(280.16 + 360.985_623_5 * days + coordinates.longitude));
+    let hour_angle = sidereal - right_ascension;
+    let latitude = to_radians(coordinates.latitude);
+
+    let elevation = asin(sin(latitude) * sin(declination) + cos(latitude) * cos(declination) * cos(hour_angle));
+    // Measured from south, turned round to be from north
+    let azimuth = atan2(sin(hour_angle), cos(hour_angle) * sin(latitude) - tan(declination) * cos(latitude));
+
+    SunPosition { azimuth: normalize_degrees(to_degrees(azimuth) + 180.), elevation: to_degrees(elevation) }
+}
+
+fn to_offset_date_time(julian: f64, offset: UtcOffset) -> OffsetDateTime {
+    let timestamp = ((julian - JULIAN_UNIX_EPOCH) * SECONDS_PER_DAY) as i64;
+    OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH).to_offset(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Month, Time};
+
+    use super::*;
+
+    const MAX_ERROR_MINUTES: i64 = 3;
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    fn offset(hours: i8) -> UtcOffset {
+        UtcOffset::from_hms(hours, 0, 0).unwrap()
+    }
+
+    #[track_caller]
+    fn assert_event(
+        (latitude, longitude): (f64, f64),
+        date: Date,
+        offset: UtcOffset,
+        event: SolarEvent,
+        (hour, minute): (u8, u8),
+    ) {
+        let coordinates = Coordinates::new(latitude, longitude).unwrap();
+        let time = event_time(coordinates, date, offset, event).unwrap();
+        let expected = date.with_time(Time::from_hms(hour, minute, 0).unwrap()).assume_offset(offset);
+
+        assert_eq!(time.offset(), offset);
+        assert_eq!(time.date(), date, "{event:?} at {time}");
+        assert!(
+            (time - expected).whole_minutes().abs() <= MAX_ERROR_MINUTES,
+            "{event:?} at {time}, expected {expected}"
+        );
+    }
+
+    const LONDON: (f64, f64) = (51.5074, -0.1278);
+    const NEW_YORK: (f64, f64) = (40.7128, -74.006);
+    const SYDNEY: (f64, f64) = (-33.8688, 151.2093);
+    const HONOLULU: (f64, f64) = (21.3069, -157.8583);
+    const TROMSO: (f64, f64) = (69.6492, 18.9553);
+
+    #[test]
+    fn london() {
+        let summer = date(2024, Month::June, 21);
+        assert_event(LONDON, summer, offset(1), SolarEvent::Sunrise, (4, 43));
+        assert_event(LONDON, summer, offset(1), SolarEvent::Sunset, (21, 21));
+        assert_event(LONDON, summer, offset(1), SolarEvent::CivilDusk, (22, 7));
+
+        let winter = date(2024, Month::December, 21);
+        assert_event(LONDON, winter, offset(0), SolarEvent::Sunrise, (8, 4));
+        assert_event(LONDON, winter, offset(0), SolarEvent::Sunset, (15, 53));
+        assert_event(LONDON, winter, offset(0), SolarEvent::CivilDawn, (7, 25));
+        assert_event(LONDON, winter, offset(0), SolarEvent::NauticalDawn, (6, 41));
+        assert_event(LONDON, winter, offset(0), SolarEvent::AstronomicalDusk, (17, 57));
+    }
+
+    #[test]
+    fn same_instant_whatever_the_offset() {
+        let coordinates = Coordinates::new(LONDON.0, LONDON.1).unwrap();
+        let day = date(2024, Month::March, 1);
+        let utc = event_time(coordinates, day, offset(0), SolarEvent::Sunset).unwrap();
+        let bst = event_time(coordinates, day, offset(1), SolarEvent::Sunset).unwrap();
+
+        assert_eq!(utc, bst);
+        assert_eq!(bst.hour(), utc.hour() + 1);
+    }
+
+    #[test]
+    fn western_hemisphere() {
+        let summer = date(2024, Month::June, 21);
+        assert_event(NEW_YORK, summer, offset(-4), SolarEvent::Sunrise, (5, 25));
+        assert_event(NEW_YORK, summer, offset(-4), SolarEvent::Sunset, (20, 31));
+
+        // Sunset is on the following UTC date
+        assert_event(HONOLULU, summer, offset(-10), SolarEvent::Sunrise, (5, 51));
+        assert_event(HONOLULU, summer, offset(-10), SolarEvent::Sunset, (19, 16));
+    }
+
+    #[test]
+    fn southern_hemisphere() {
+        // Sunrise is on the previous UTC date
+        let summer = date(2024, Month::December, 21);
+        assert_event(SYDNEY, summer, offset(11), SolarEvent::Sunrise, (5, 42));
+        assert_event(SYDNEY, summer, offset(11), SolarEvent::Sunset, (20, 5));
+
+        let winter = date(2024, Month::June, 21);
+        assert_event(SYDNEY, winter, offset(10), SolarEvent::Sunrise, (7, 0));
+        assert_event(SYDNEY, winter, offset(10), SolarEvent::Sunset, (16, 54));
+    }
+
+    #[test]
+    fn polar() {
+        let coordinates = Coordinates::new(TROMSO.0, TROMSO.1).unwrap();
+
+        let summer = date(2024, Month::June, 21);
+        for (event, time) in events(coordinates, summer, offset(2)) {
+            assert_eq!(time, Err(NoEvent::AlwaysAbove), "{event:?}");
+        }
+
+        let winter = date(2024, Month::December, 21);
+        assert_eq!(event_time(coordinates, winter, offset(1), SolarEvent::Sunrise), Err(NoEvent::AlwaysBelow));
+        assert_eq!(event_time(coordinates, winter, offset(1), SolarEvent::Sunset), Err(NoEvent::AlwaysBelow));
+        // Still gets light enough for civil twilight around midday
+        let dawn = event_time(coordinates, winter, offset(1), SolarEvent::CivilDawn).unwrap();
+        let dusk = event_time(coordinates, winter, offset(1), SolarEvent::CivilDusk).unwrap();
+        assert!(dawn < dusk);
+        assert!((9..12).contains(&dawn.hour()), "{dawn}");
+        assert!((12..15).contains(&dusk.hour()), "{dusk}");
+    }
+
+    #[track_caller]
+    fn assert_position((latitude, longitude): (f64, f64), time: OffsetDateTime, azimuth: f64, elevation: f64) {
+        let coordinates = Coordinates::new(latitude, longitude).unwrap();
+        let position = sun_position(coordinates, time);
+        assert!((position.azimuth - azimuth).abs() < 1., "{position:?}, expected azimuth {azimuth}");
+        assert!((position.elevation - elevation).abs() < 1., "{position:?}, expected elevation {elevation}");
+    }
+
+    #[test]
+    fn position() {
+        let noon = date(2024, Month::June, 21).with_time(Time::from_hms(13, 2, 0).unwrap()).assume_offset(offset(1));
+        assert_position(LONDON, noon, 180., 61.9);
+
+        let morning = date(2024, Month::June, 21).with_time(Time::from_hms(9, 0, 0).unwrap()).assume_offset(offset(-4));
+        assert_position(NEW_YORK, morning, 90.8, 37.6);
+
+        // The sun is to the north at midday in the southern hemisphere
+        let noon = date(2024, Month::December, 21).with_time(Time::from_hms(12, 53, 0).unwrap()).assume_offset(offset(11));
+        assert_position(SYDNEY, noon, 0., 79.6);
+
+        let midnight = date(2024, Month::June, 21).with_time(Time::from_hms(0, 0, 0).unwrap()).assume_offset(offset(1));
+        assert!(sun_position(Coordinates::new(LONDON.0, LONDON.1).unwrap(), midnight).elevation < -10.);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(Coordinates::new(91., 0.), None);
+        assert_eq!(Coordinates::new(0., -181.), None);
+    }
+}
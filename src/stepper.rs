@@ -0,0 +1,121 @@
+//! Step pin output, either bit banged from a task or generated in hardware by the RMT peripheral
+
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Output;
+#[cfg(feature = "rmt")]
+use esp_hal::{
+    gpio::interconnect::PeripheralOutput,
+    peripheral::Peripheral,
+    peripherals::RMT,
+    rmt::{Channel, Rmt, TxChannelAsync, TxChannelConfig, TxChannelCreatorAsync},
+    time::RateExtU32,
+    Async,
+};
+#[cfg(feature = "rmt")]
+use log::debug;
+
+#[cfg(feature = "rmt")]
+use crate::pulse::{encode, Encoded};
+
+/// Something that can toggle the step pin with given delays between toggles
+#[allow(async_fn_in_trait)]
+pub trait StepOutput {
+    /// Number of toggles that should be handed to [`toggle`][Self::toggle] at once. Callers check
+    /// for new commands between batches so this bounds how quickly a move can be stopped
+    const BATCH: usize;
+
+    /// Toggles the step pin once per interval, waiting the interval (in microseconds) after each
+    /// toggle. Batches should contain an even number of toggles so the pin ends up low
+    async fn toggle(&mut self, intervals: &[u32]) -> Result<(), Error>;
+}
+
+/// Bit bangs the step pin using embassy timers
+pub struct GpioStepper {
+    step: Output<'static>,
+}
+
+impl GpioStepper {
+    pub fn new(step: Output<'static>) -> Self {
+        Self { step }
+    }
+}
+
+impl StepOutput for GpioStepper {
+    const BATCH: usize = 2;
+
+    async fn toggle(&mut self, intervals: &[u32]) -> Result<(), Error> {
+        for &interval in intervals {
+            self.step.toggle();
+            Timer::after(Duration::from_micros(interval as u64)).await;
+        }
+        Ok(())
+    }
+}
+
+/// RMT clock, the divider below brings this down to 1 tick per microsecond
+#[cfg(feature = "rmt")]
+const RMT_FREQUENCY_MHZ: u32 = 80;
+#[cfg(feature = "rmt")]
+const TICKS_PER_US: u32 = 1;
+/// Codes that fit in the RAM block of a single channel
+#[cfg(feature = "rmt")]
+const RMT_CODES: usize = 64;
+
+/// Generates the step pulses in hardware with RMT channel 0
+#[cfg(feature = "rmt")]
+pub struct RmtStepper {
+    channel: Channel<Async, 0>,
+    codes: [u32; RMT_CODES],
+    /// Level of the next interval, carried over when a batch is sent in several transmissions
+    level: bool,
+}
+
+#[cfg(feature = "rmt")]
+impl RmtStepper {
+    pub fn new<P: PeripheralOutput>(
+        rmt: RMT,
+        step: impl Peripheral<P = P> + 'static,
+    ) -> Result<Self, Error> {
+        let rmt = Rmt::new(rmt, RMT_FREQUENCY_MHZ.MHz())?.into_async();
+        let config = TxChannelConfig {
+            clk_divider: (RMT_FREQUENCY_MHZ / TICKS_PER_US) as u8,
+            idle_output_level: false,
+            idle_output: true,
+            ..TxChannelConfig::default()
+        };
+        let channel = rmt.channel0.configure(step, config)?;
+        debug!("RMT step output configured");
+
+        // The first toggle drives the pin high from idle
+        Ok(Self { channel, codes: [0; RMT_CODES], level: true })
+    }
+}
+
+#[cfg(feature = "rmt")]
+impl StepOutput for RmtStepper {
+    // Leaves space in the channel RAM for intervals that need splitting across several codes
+    const BATCH: usize = 96;
+
+    async fn toggle(&mut self, mut intervals: &[u32]) -> Result<(), Error> {
+        while !intervals.is_empty() {
+            let Encoded { intervals: n, codes, next_level } = encode(intervals, TICKS_PER_US, self.level, &mut self.codes);
+            self.channel.transmit(&self.codes[..codes]).await?;
+            self.level = next_level;
+            intervals = &intervals[n..];
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "rmt")]
+    Rmt(esp_hal::rmt::Error),
+}
+
+#[cfg(feature = "rmt")]
+impl From<esp_hal::rmt::Error> for Error {
+    fn from(value: esp_hal::rmt::Error) -> Self {
+        Self::Rmt(value)
+    }
+}
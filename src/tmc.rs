@@ -0,0 +1,471 @@
+//! Configuration and diagnostics for TMC2208/TMC2209 stepper drivers over their single wire UART
+//!
+//! Only depends on the `embedded-io-async` traits so the framing can be exercised against a mock
+//! serial port. Callers are responsible for timeouts as a missing driver never replies.
+
+use embedded_io_async::{Read, ReadExactError, Write};
+
+const SYNC: u8 = 0x05;
+/// Address the driver uses for replies
+const MASTER_ADDRESS: u8 = 0xff;
+const WRITE_BIT: u8 = 0x80;
+
+const READ_REQUEST_LEN: usize = 4;
+const DATAGRAM_LEN: usize = 8;
+
+/// Register addresses
+pub mod reg {
+    pub const GCONF: u8 = 0x00;
+    pub const GSTAT: u8 = 0x01;
+    pub const IFCNT: u8 = 0x02;
+    pub const IHOLD_IRUN: u8 = 0x10;
+    pub const TPOWERDOWN: u8 = 0x11;
+    pub const TSTEP: u8 = 0x12;
+    pub const TPWMTHRS: u8 = 0x13;
+    pub const TCOOLTHRS: u8 = 0x14;
+    pub const SGTHRS: u8 = 0x40;
+    pub const SG_RESULT: u8 = 0x41;
+    pub const CHOPCONF: u8 = 0x6c;
+    pub const DRV_STATUS: u8 = 0x6f;
+    pub const PWMCONF: u8 = 0x70;
+}
+
+// GCONF bits
+const GCONF_EN_SPREADCYCLE: u32 = 1 << 2;
+const GCONF_PDN_DISABLE: u32 = 1 << 6;
+const GCONF_MSTEP_REG_SELECT: u32 = 1 << 7;
+const GCONF_MULTISTEP_FILT: u32 = 1 << 8;
+
+/// CHOPCONF reset value with the microstep resolution cleared
+const CHOPCONF_DEFAULT: u32 = 0x1000_0053 & !CHOPCONF_MRES_MASK;
+const CHOPCONF_MRES_SHIFT: u32 = 24;
+const CHOPCONF_MRES_MASK: u32 = 0xf << CHOPCONF_MRES_SHIFT;
+
+/// CRC8 (polynomial x^8 + x^2 + x + 1) as described in the datasheet. Bytes are processed LSB first
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0_u8;
+    for &byte in bytes {
+        let mut byte = byte;
+        for _ in 0..8 {
+            crc = if (crc >> 7) ^ (byte & 0x01) != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// Builds the datagram that writes `value` to `register`
+pub fn write_datagram(address: u8, register: u8, value: u32) -> [u8; DATAGRAM_LEN] {
+    let mut datagram = [0; DATAGRAM_LEN];
+    datagram[0] = SYNC;
+    datagram[1] = address;
+    datagram[2] = register | WRITE_BIT;
+    datagram[3..7].copy_from_slice(&value.to_be_bytes());
+    datagram[7] = crc8(&datagram[..7]);
+    datagram
+}
+
+/// Builds the datagram that requests the value of `register`
+pub fn read_request(address: u8, register: u8) -> [u8; READ_REQUEST_LEN] {
+    let mut datagram = [SYNC, address, register & !WRITE_BIT, 0];
+    datagram[3] = crc8(&datagram[..3]);
+    datagram
+}
+
+/// Validates a reply to a read request and extracts the register value
+pub fn parse_reply(register: u8, reply: &[u8; DATAGRAM_LEN]) -> Result<u32, FrameError> {
+    if reply[7] != crc8(&reply[..7]) {
+        return Err(FrameError::Crc);
+    }
+    if reply[0] & 0x0f != SYNC || reply[1] != MASTER_ADDRESS || reply[2] != register {
+        return Err(FrameError::Header);
+    }
+
+    let mut value = [0; 4];
+    value.copy_from_slice(&reply[3..7]);
+    Ok(u32::from_be_bytes(value))
+}
+
+/// Problems with a datagram received from the driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    Crc,
+    /// Sync, address or register didn't match the request
+    Header,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    UnexpectedEof,
+    Frame(FrameError),
+    /// The echo of a request on the single wire bus didn't match what was sent
+    Echo,
+    /// The interface counter didn't advance by the number of writes made
+    WriteCount { expected: u8, actual: u8 },
+    /// Microstep resolution isn't a power of 2 between 1 and 256
+    InvalidMicrosteps(u16),
+}
+
+impl<E> From<ReadExactError<E>> for Error<E> {
+    fn from(value: ReadExactError<E>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(e) => Self::Io(e),
+        }
+    }
+}
+
+impl<E> From<FrameError> for Error<E> {
+    fn from(value: FrameError) -> Self {
+        Self::Frame(value)
+    }
+}
+
+/// Settings applied by [`Tmc::configure`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Microsteps per full step, a power of 2 up to 256
+    pub microsteps: u16,
+    /// Current scale (0-31) while moving
+    pub run_current: u8,
+    /// Current scale (0-31) while stationary
+    pub hold_current: u8,
+    /// Delay before dropping to the hold current, in units of 2^18 clocks (0-15)
+    pub hold_delay: u8,
+    /// Use StealthChop (quiet) rather than SpreadCycle
+    pub stealth_chop: bool,
+}
+
+/// Converts an RMS current into the 0-31 current scale for a given sense resistor, assuming the
+/// default (high) sense voltage range
+pub fn current_scale(milliamps: u32, sense_milliohms: u32) -> u8 {
+    const V_FS: f32 = 0.325;
+    const SQRT_2: f32 = core::f32::consts::SQRT_2;
+
+    let amps = milliamps as f32 / 1000.;
+    let ohms = (sense_milliohms as f32 + 20.) / 1000.;
+    let scale = 32. * SQRT_2 * amps * ohms / V_FS - 1.;
+
+    (scale.max(0.) as u8).min(31)
+}
+
+/// Decoded DRV_STATUS register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrvStatus {
+    pub over_temperature_warning: bool,
+    pub over_temperature: bool,
+    pub short_to_ground_a: bool,
+    pub short_to_ground_b: bool,
+    pub short_low_side_a: bool,
+    pub short_low_side_b: bool,
+    pub open_load_a: bool,
+    pub open_load_b: bool,
+    /// Actual current scale
+    pub current_scale: u8,
+    pub stealth_chop: bool,
+    pub standstill: bool,
+}
+
+impl From<u32> for DrvStatus {
+    fn from(value: u32) -> Self {
+        let bit = |n: u32| value & (1 << n) != 0;
+        Self {
+            over_temperature_warning: bit(0),
+            over_temperature: bit(1),
+            short_to_ground_a: bit(2),
+            short_to_ground_b: bit(3),
+            short_low_side_a: bit(4),
+            short_low_side_b: bit(5),
+            open_load_a: bit(6),
+            open_load_b: bit(7),
+            current_scale: ((value >> 16) & 0x1f) as u8,
+            stealth_chop: bit(30),
+            standstill: bit(31),
+        }
+    }
+}
+
+impl DrvStatus {
+    /// True for conditions that stop the driver. Open load is excluded as it's also reported at
+    /// standstill and low speeds
+    pub fn fault(&self) -> bool {
+        self.over_temperature
+            || self.short_to_ground_a
+            || self.short_to_ground_b
+            || self.short_low_side_a
+            || self.short_low_side_b
+    }
+}
+
+/// A driver on a single wire UART bus
+pub struct Tmc<U> {
+    uart: U,
+    address: u8,
+    /// Whether the bus reads back what is written, as it does when TX and RX share a wire
+    echo: bool,
+}
+
+impl<U: Read + Write> Tmc<U> {
+    pub fn new(uart: U, address: u8, echo: bool) -> Self {
+        Self { uart, address, echo }
+    }
+
+    async fn send(&mut self, datagram: &[u8]) -> Result<(), Error<U::Error>> {
+        self.uart.write_all(datagram).await.map_err(Error::Io)?;
+        self.uart.flush().await.map_err(Error::Io)?;
+
+        if self.echo {
+            let mut echo = [0; DATAGRAM_LEN];
+            let echo = &mut echo[..datagram.len()];
+            self.uart.read_exact(echo).await?;
+            if echo != datagram {
+                return Err(Error::Echo);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_register(&mut self, register: u8, value: u32) -> Result<(), Error<U::Error>> {
+        self.send(&write_datagram(self.address, register, value)).await
+    }
+
+    pub async fn read_register(&mut self, register: u8) -> Result<u32, Error<U::Error>> {
+        self.send(&read_request(self.address, register)).await?;
+
+        let mut reply = [0; DATAGRAM_LEN];
+        self.uart.read_exact(&mut reply).await?;
+
+        Ok(parse_reply(register, &reply)?)
+    }
+
+    /// Applies `config`, checking the interface counter to confirm every write was accepted
+    pub async fn configure(&mut self, config: &Config) -> Result<(), Error<U::Error>> {
+        let mres = match config.microsteps {
+            n @ 1..=256 if n.is_power_of_two() => 8 - n.trailing_zeros(),
+            n => return Err(Error::InvalidMicrosteps(n)),
+        };
+
+        let mut gconf = GCONF_PDN_DISABLE | GCONF_MSTEP_REG_SELECT | GCONF_MULTISTEP_FILT;
+        if !config.stealth_chop {
+            gconf |= GCONF_EN_SPREADCYCLE;
+        }
+
+        let chopconf = CHOPCONF_DEFAULT | (mres << CHOPCONF_MRES_SHIFT);
+        let ihold_irun = (config.hold_current.min(31) as u32)
+            | ((config.run_current.min(31) as u32) << 8)
+            | ((config.hold_delay.min(15) as u32) << 16);
+
+        let writes = [(reg::GCONF, gconf), (reg::CHOPCONF, chopconf), (reg::IHOLD_IRUN, ihold_irun)];
+
+        let before = self.read_register(reg::IFCNT).await? as u8;
+        for (register, value) in writes {
+            self.write_register(register, value).await?;
+        }
+        let after = self.read_register(reg::IFCNT).await? as u8;
+
+        let expected = before.wrapping_add(writes.len() as u8);
+        if after != expected {
+            return Err(Error::WriteCount { expected, actual: after });
+        }
+
+        Ok(())
+    }
+
+    /// Enables StallGuard (TMC2209 only). The DIAG pin goes high when the load value drops below
+    /// twice `threshold` while the time between steps (TSTEP) is at most `min_speed_tstep`.
+    /// StallGuard only works in StealthChop
+    pub async fn configure_stall_guard(&mut self, threshold: u8, min_speed_tstep: u32) -> Result<(), Error<U::Error>> {
+        self.write_register(reg::TCOOLTHRS, min_speed_tstep & 0xf_ffff).await?;
+        self.write_register(reg::SGTHRS, threshold as u32).await
+    }
+
+    /// The current StallGuard load value (TMC2209 only), lower means more load
+    pub async fn stall_guard_result(&mut self) -> Result<u16, Error<U::Error>> {
+        Ok((self.read_register(reg::SG_RESULT).await? & 0x3ff) as u16)
+    }
+
+    pub async fn drv_status(&mut self) -> Result<DrvStatus, Error<U::Error>> {
+        Ok(self.read_register(reg::DRV_STATUS).await?.into())
+    }
+
+    /// Reads and clears the global status flags (reset, driver error, charge pump undervoltage)
+    pub async fn take_gstat(&mut self) -> Result<u32, Error<U::Error>> {
+        let gstat = self.read_register(reg::GSTAT).await?;
+        self.write_register(reg::GSTAT, gstat).await?;
+        Ok(gstat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    use embedded_io_async::ErrorType;
+    use heapless::{Deque, Vec};
+
+    use super::*;
+
+    /// Serial port that records what is written and replays queued bytes
+    #[derive(Default)]
+    struct MockUart {
+        written: Vec<u8, 64>,
+        /// Read before `rx`, filled by writes when `echo` is set
+        echoed: Deque<u8, 64>,
+        rx: Deque<u8, 64>,
+        echo: bool,
+    }
+
+    impl MockUart {
+        fn reply(&mut self, register: u8, value: u32) {
+            let mut reply = [SYNC, MASTER_ADDRESS, register, 0, 0, 0, 0, 0];
+            reply[3..7].copy_from_slice(&value.to_be_bytes());
+            reply[7] = crc8(&reply[..7]);
+            self.queue(&reply);
+        }
+
+        fn queue(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.rx.push_back(byte).unwrap();
+            }
+        }
+    }
+
+    impl ErrorType for MockUart {
+        type Error = Infallible;
+    }
+
+    impl Read for MockUart {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.echoed.pop_front().or_else(|| self.rx.pop_front()) {
+                    Some(byte) => buf[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MockUart {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            for &byte in buf {
+                self.written.push(byte).unwrap();
+                if self.echo {
+                    self.echoed.push_back(byte).unwrap();
+                }
+            }
+            Ok(buf.len())
+        }
+    }
+
+    /// Polls a future that never waits, as nothing the mock does can
+    fn block_on<F: Future>(future: F) -> F::Output {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+        const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+
+        let waker = unsafe { Waker::from_raw(RAW) };
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Mock UART blocked"),
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc8(&[]), 0);
+        // Reading GCONF from address 0, as given in the datasheet
+        assert_eq!(crc8(&[0x05, 0x00, 0x00]), 0x48);
+        assert_eq!(read_request(0, reg::GCONF), [0x05, 0x00, 0x00, 0x48]);
+        assert_eq!(read_request(0, reg::IFCNT), [0x05, 0x00, 0x02, 0x8f]);
+    }
+
+    #[test]
+    fn write_frame() {
+        let frame = [0x05, 0x00, 0x80, 0x00, 0x00, 0x00, 0xc0, 0x40];
+        assert_eq!(write_datagram(0, reg::GCONF, 0xc0), frame);
+
+        let mut tmc = Tmc::new(MockUart::default(), 0, false);
+        block_on(tmc.write_register(reg::GCONF, 0xc0)).unwrap();
+        assert_eq!(tmc.uart.written, frame);
+
+        let frame = write_datagram(3, reg::SGTHRS, 0x1234_5678);
+        assert_eq!(frame[..7], [0x05, 0x03, 0xc0, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(frame[7], crc8(&frame[..7]));
+    }
+
+    #[test]
+    fn read_reply() {
+        let mut uart = MockUart::default();
+        uart.queue(&[0x05, 0xff, 0x02, 0x00, 0x00, 0x00, 0x07, 0xe2]);
+        let mut tmc = Tmc::new(uart, 0, false);
+
+        assert_eq!(block_on(tmc.read_register(reg::IFCNT)).unwrap(), 7);
+        assert_eq!(tmc.uart.written, read_request(0, reg::IFCNT));
+        assert!(tmc.uart.rx.is_empty());
+    }
+
+    #[test]
+    fn read_reply_with_echo() {
+        let mut uart = MockUart { echo: true, ..MockUart::default() };
+        uart.reply(reg::DRV_STATUS, 0xc010_0002);
+        let mut tmc = Tmc::new(uart, 1, true);
+
+        let status = block_on(tmc.drv_status()).unwrap();
+        assert!(status.over_temperature && status.stealth_chop && status.standstill && status.fault());
+        assert_eq!(status.current_scale, 16);
+        assert_eq!(tmc.uart.written, read_request(1, reg::DRV_STATUS));
+    }
+
+    #[test]
+    fn bad_replies() {
+        let mut reply = [0x05, 0xff, 0x02, 0x00, 0x00, 0x00, 0x07, 0xe2];
+        reply[6] = 0x08;
+        assert_eq!(parse_reply(reg::IFCNT, &reply), Err(FrameError::Crc));
+
+        let reply = [0x05, 0xff, 0x02, 0x00, 0x00, 0x00, 0x07, 0xe2];
+        assert_eq!(parse_reply(reg::GSTAT, &reply), Err(FrameError::Header));
+
+        let mut tmc = Tmc::new(MockUart::default(), 0, false);
+        assert!(matches!(block_on(tmc.read_register(reg::IFCNT)), Err(Error::UnexpectedEof)));
+
+        // Another driver answering on the bus corrupts the echo
+        let mut uart = MockUart::default();
+        uart.queue(&[0x05, 0x01, 0x02, 0x00]);
+        let mut tmc = Tmc::new(uart, 0, true);
+        assert!(matches!(block_on(tmc.read_register(reg::IFCNT)), Err(Error::Echo)));
+    }
+
+    #[test]
+    fn configure_checks_write_count() {
+        let config = Config { microsteps: 16, run_current: 16, hold_current: 4, hold_delay: 8, stealth_chop: true };
+
+        let mut uart = MockUart::default();
+        uart.reply(reg::IFCNT, 0xfe);
+        uart.reply(reg::IFCNT, 0x01);
+        let mut tmc = Tmc::new(uart, 0, false);
+        block_on(tmc.configure(&config)).unwrap();
+        // Two read requests around three writes
+        assert_eq!(tmc.uart.written.len(), 2 * READ_REQUEST_LEN + 3 * DATAGRAM_LEN);
+        assert_eq!(tmc.uart.written[4..12], write_datagram(0, reg::GCONF, 0x1c0));
+
+        let mut uart = MockUart::default();
+        uart.reply(reg::IFCNT, 7);
+        uart.reply(reg::IFCNT, 9);
+        let mut tmc = Tmc::new(uart, 0, false);
+        assert!(matches!(
+            block_on(tmc.configure(&config)),
+            Err(Error::WriteCount { expected: 10, actual: 9 })
+        ));
+
+        let mut tmc = Tmc::new(MockUart::default(), 0, false);
+        let config = Config { microsteps: 3, ..config };
+        assert!(matches!(block_on(tmc.configure(&config)), Err(Error::InvalidMicrosteps(3))));
+    }
+}
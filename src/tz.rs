@@ -0,0 +1,595 @@
+//! Time zones written as POSIX TZ strings
+//!
+//! A zone is `<std><offset>[<dst>[<offset>][,<start>[/<time>],<end>[/<time>]]]` such as
+//! `CET-1CEST,M3.5.0,M10.5.0/3`. Offsets are hours west of UTC as in POSIX, so central Europe is
+//! `-1`, and the DST offset defaults to an hour ahead of standard time. Rules are `Mm.w.d` for day
+//! `d` (0 is Sunday) of week `w` (5 is the last) of month `m`, `Jn` for day `n` (1-365) not
+//! counting February 29th, or `n` for day `n` (0-365) counting it. Transitions happen at 02:00 local
+//! time unless a time is given. Names that aren't all letters are quoted with `<>`, e.g. `<+03>-3`.
+//!
+//! Zones can also be given by their IANA name, such as `Europe/Berlin`, which is looked up in a table
+//! of the current rules of each zone generated by `build.rs` from `zones.csv`.
+
+use core::{fmt, str::FromStr};
+
+use heapless::String;
+
+pub const MAX_NAME_LEN: usize = 10;
+/// Longest text form of a zone, a full length name, offset and rule for both standard and daylight
+/// saving time fits with room to spare
+pub const MAX_TZ_LEN: usize = 96;
+/// Length of an encoded zone, the length of the text form followed by the text padded to full length
+pub const TZ_LEN: usize = 1 + MAX_TZ_LEN;
+
+const SECONDS_PER_HOUR: i32 = 60 * 60;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// Transitions happen at 02:00 local time by default
+const DEFAULT_TRANSITION_TIME: i32 = 2 * SECONDS_PER_HOUR;
+/// Largest offset from UTC in hours
+const MAX_OFFSET_HOURS: u32 = 24;
+/// Largest transition time in hours either side of midnight, the extension to POSIX from RFC 8536
+const MAX_TRANSITION_HOURS: u32 = 167;
+
+pub type Name = String<MAX_NAME_LEN>;
+
+mod zones {
+    include!(concat!(env!("OUT_DIR"), "/zones.rs"));
+
+    /// The IANA name and rules of the zone called `name`
+    pub fn lookup(name: &str) -> Option<(&'static str, &'static str)> {
+        let index = ZONES.binary_search_by(|&(zone, _)| zone.cmp(name)).ok()?;
+        let (name, rule) = ZONES[index];
+        Some((name, RULES[rule as usize]))
+    }
+}
+
+pub use zones::lookup;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    /// IANA name such as `Europe/London` when the zone was given by name
+    pub iana: Option<&'static str>,
+    pub name: Name,
+    /// Offset of standard time in seconds east of UTC
+    pub offset: i32,
+    pub dst: Option<Dst>,
+}
+
+/// Daylight saving time and when it applies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dst {
+    pub name: Name,
+    /// Offset of daylight saving time in seconds east of UTC
+    pub offset: i32,
+    /// Start in standard time
+    pub start: Transition,
+    /// End in daylight saving time
+    pub end: Transition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub day: TransitionDay,
+    /// Seconds after local midnight, which may be negative or past the end of the day
+    pub time: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionDay {
+    /// `Jn`, day 1-365 never counting February 29th
+    Julian(u16),
+    /// `n`, day 0-365 counting February 29th in leap years
+    Zero(u16),
+    /// `Mm.w.d`, weekday `weekday` (0 is Sunday) of week `week` (1-5, 5 is the last) of `month` (1-12)
+    Month { month: u8, week: u8, weekday: u8 },
+}
+
+impl TimeZone {
+    /// Offset from UTC in seconds east at `time`, a unix timestamp
+    pub fn offset_at(&self, time: i64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.in_dst(dst, time) => dst.offset,
+            _ => self.offset,
+        }
+    }
+
+    /// Name of the time in force at `time`, a unix timestamp
+    pub fn name_at(&self, time: i64) -> &str {
+        match &self.dst {
+            Some(dst) if self.in_dst(dst, time) => &dst.name,
+            _ => &self.name,
+        }
+    }
+
+    /// The first change of offset after `time`, as a unix timestamp. `None` without daylight saving
+    pub fn next_transition(&self, time: i64) -> Option<i64> {
+        let dst = self.dst.as_ref()?;
+        let year = year_of(time + self.offset as i64);
+        (year..=year + 1)
+            .flat_map(|year| [self.start_of(dst, year), self.end_of(dst, year)])
+            .filter(|&transition| transition > time)
+            .min()
+    }
+
+    /// Start of daylight saving time in `year` as a unix timestamp
+    fn start_of(&self, dst: &Dst, year: i32) -> i64 {
+        dst.start.local(year) - self.offset as i64
+    }
+
+    /// End of daylight saving time in `year` as a unix timestamp
+    fn end_of(&self, dst: &Dst, year: i32) -> i64 {
+        dst.end.local(year) - dst.offset as i64
+    }
+
+    fn in_dst(&self, dst: &Dst, time: i64) -> bool {
+        let year = year_of(time + self.offset as i64);
+        let start = self.start_of(dst, year);
+        let end = self.end_of(dst, year);
+        if start < end {
+            start <= time && time < end
+        } else {
+            // Southern hemisphere, daylight saving time spans the new year
+            !(end <= time && time < start)
+        }
+    }
+
+    pub fn encode(&self) -> [u8; TZ_LEN] {
+        let mut text = String::<MAX_TZ_LEN>::new();
+        // Can't fail, MAX_TZ_LEN is more than the longest zone
+        let _ = fmt::Write::write_fmt(&mut text, format_args!("{self}"));
+
+        let mut buf = [0; TZ_LEN];
+        buf[0] = text.len() as u8;
+        buf[1..1 + text.len()].copy_from_slice(text.as_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; TZ_LEN]) -> Option<Self> {
+        let len = buf[0] as usize;
+        if len > MAX_TZ_LEN {
+            return None;
+        }
+        core::str::from_utf8(&buf[1..1 + len]).ok()?.parse().ok()
+    }
+}
+
+impl Transition {
+    /// The transition in `year` as seconds since the unix epoch in local time
+    fn local(&self, year: i32) -> i64 {
+        let leap = is_leap_year(year);
+        let day = match self.day {
+            TransitionDay::Julian(day) => day as i64 - 1 + (leap && day >= 60) as i64,
+            TransitionDay::Zero(day) => day as i64,
+            TransitionDay::Month { month, week, weekday } => {
+                let first = days_from_civil(year, month as u32, 1);
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = (weekday as i64 - first_weekday).rem_euclid(7) + (week as i64 - 1) * 7;
+                while day >= month_length(year, month) as i64 {
+                    day -= 7;
+                }
+                first - days_from_civil(year, 1, 1) + day
+            },
+        };
+        (days_from_civil(year, 1, 1) + day) * SECONDS_PER_DAY + self.time as i64
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn month_length(year: i32, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year of `time`, seconds since the unix epoch
+fn year_of(time: i64) -> i32 {
+    let days = time.div_euclid(SECONDS_PER_DAY) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Years are counted from March so January and February belong to the next one
+    let march_based_month = (5 * day_of_year + 2) / 153;
+    (year_of_era + era * 400 + (march_based_month >= 10) as i64) as i32
+}
+
+/// Problems with the text form of a zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseTzError {
+    /// Expected 3 to 10 letters, or letters, digits, `+` and `-` between `<` and `>`
+    Name,
+    /// Expected `[+-]hh[:mm[:ss]]` of up to 24 hours
+    Offset,
+    /// Expected `Mm.w.d`, `Jn` or `n` with an optional `/hh[:mm[:ss]]`
+    Rule,
+    /// Trailing text after the zone
+    TooLong,
+    /// Not a zone in the table of IANA names
+    UnknownZone,
+}
+
+/// Splits `s` into the text at its start that `f` accepts and the rest
+fn split_while(s: &str, f: impl Fn(char) -> bool) -> (&str, &str) {
+    s.split_at(s.find(|c| !f(c)).unwrap_or(s.len()))
+}
+
+fn parse_name(s: &str) -> Result<(Name, &str), ParseTzError> {
+    let (name, rest) = match s.strip_prefix('<') {
+        Some(quoted) => {
+            let (name, rest) = quoted.split_once('>').ok_or(ParseTzError::Name)?;
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-') {
+                return Err(ParseTzError::Name);
+            }
+            (name, rest)
+        },
+        None => split_while(s, |c| c.is_ascii_alphabetic()),
+    };
+    if name.len() < 3 {
+        return Err(ParseTzError::Name);
+    }
+    Ok((name.try_into().map_err(|()| ParseTzError::Name)?, rest))
+}
+
+/// Parses `[+-]hh[:mm[:ss]]` into seconds, with at most `max_hours` hours
+fn parse_time(s: &str, max_hours: u32) -> Option<(i32, &str)> {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => (-1, s),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let (hours, mut rest) = split_while(s, |c| c.is_ascii_digit());
+    let hours: u32 = hours.parse().ok().filter(|&hours| hours <= max_hours)?;
+    let mut seconds = hours * 3600;
+    for scale in [60, 1] {
+        let Some(field) = rest.strip_prefix(':') else {
+            break;
+        };
+        let (value, after) = split_while(field, |c| c.is_ascii_digit());
+        seconds += value.parse::<u32>().ok().filter(|&value| value < 60)? * scale;
+        rest = after;
+    }
+    Some((sign * seconds as i32, rest))
+}
+
+fn parse_number(s: &str) -> Result<(u16, &str), ParseTzError> {
+    let (number, rest) = split_while(s, |c| c.is_ascii_digit());
+    Ok((number.parse().map_err(|_| ParseTzError::Rule)?, rest))
+}
+
+fn parse_transition(s: &str) -> Result<(Transition, &str), ParseTzError> {
+    let number = parse_number;
+    let (day, rest) = if let Some(s) = s.strip_prefix('M') {
+        let (month, rest) = number(s)?;
+        let (week, rest) = number(rest.strip_prefix('.').ok_or(ParseTzError::Rule)?)?;
+        let (weekday, rest) = number(rest.strip_prefix('.').ok_or(ParseTzError::Rule)?)?;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+            return Err(ParseTzError::Rule);
+        }
+        (TransitionDay::Month { month: month as u8, week: week as u8, weekday: weekday as u8 }, rest)
+    } else if let Some(s) = s.strip_prefix('J') {
+        let (day, rest) = number(s)?;
+        if !(1..=365).contains(&day) {
+            return Err(ParseTzError::Rule);
+        }
+        (TransitionDay::Julian(day), rest)
+    } else {
+        let (day, rest) = number(s)?;
+        if day > 365 {
+            return Err(ParseTzError::Rule);
+        }
+        (TransitionDay::Zero(day), rest)
+    };
+
+    let (time, rest) = match rest.strip_prefix('/') {
+        Some(time) => parse_time(time, MAX_TRANSITION_HOURS).ok_or(ParseTzError::Rule)?,
+        None => (DEFAULT_TRANSITION_TIME, rest),
+    };
+    Ok((Transition { day, time }, rest))
+}
+
+impl FromStr for TimeZone {
+    type Err = ParseTzError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((iana, rule)) = lookup(s) {
+            return Ok(Self { iana: Some(iana), ..rule.parse()? });
+        }
+        // Rules only contain `/` after the first `,`
+        if s.split(',').next().is_some_and(|zone| zone.contains('/')) {
+            return Err(ParseTzError::UnknownZone);
+        }
+
+        let (name, rest) = parse_name(s)?;
+        // POSIX offsets are west of UTC
+        let (offset, rest) = parse_time(rest, MAX_OFFSET_HOURS).ok_or(ParseTzError::Offset)?;
+        let offset = -offset;
+        if rest.is_empty() {
+            return Ok(Self { iana: None, name, offset, dst: None });
+        }
+
+        let (dst_name, rest) = parse_name(rest)?;
+        let (dst_offset, rest) = match rest.chars().next() {
+            Some(',') | None => (offset + SECONDS_PER_HOUR, rest),
+            Some(_) => {
+                let (dst_offset, rest) = parse_time(rest, MAX_OFFSET_HOURS).ok_or(ParseTzError::Offset)?;
+                (-dst_offset, rest)
+            },
+        };
+
+        let (start, end, rest) = match rest.strip_prefix(',') {
+            Some(rules) => {
+                let (start, rest) = parse_transition(rules)?;
+                let (end, rest) = parse_transition(rest.strip_prefix(',').ok_or(ParseTzError::Rule)?)?;
+                (start, end, rest)
+            },
+            // The rules of the US, as glibc uses when there aren't any
+            None if rest.is_empty() => (
+                Transition { day: TransitionDay::Month { month: 3, week: 2, weekday: 0 }, time: DEFAULT_TRANSITION_TIME },
+                Transition { day: TransitionDay::Month { month: 11, week: 1, weekday: 0 }, time: DEFAULT_TRANSITION_TIME },
+                rest,
+            ),
+            None => return Err(ParseTzError::Rule),
+        };
+        if !rest.is_empty() {
+            return Err(ParseTzError::TooLong);
+        }
+
+        Ok(Self { iana: None, name, offset, dst: Some(Dst { name: dst_name, offset: dst_offset, start, end }) })
+    }
+}
+
+struct NameText<'a>(&'a str);
+
+impl fmt::Display for NameText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.chars().all(|c| c.is_ascii_alphabetic()) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "<{}>", self.0)
+        }
+    }
+}
+
+/// Seconds as `[-]h[:mm[:ss]]`
+struct TimeText(i32);
+
+impl fmt::Display for TimeText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+        let total = self.0.unsigned_abs();
+        let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+        match (minutes, seconds) {
+            (0, 0) => write!(f, "{hours}"),
+            (_, 0) => write!(f, "{hours}:{minutes:02}"),
+            _ => write!(f, "{hours}:{minutes:02}:{seconds:02}"),
+        }
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.day {
+            TransitionDay::Julian(day) => write!(f, "J{day}")?,
+            TransitionDay::Zero(day) => write!(f, "{day}")?,
+            TransitionDay::Month { month, week, weekday } => write!(f, "M{month}.{week}.{weekday}")?,
+        }
+        if self.time != DEFAULT_TRANSITION_TIME {
+            write!(f, "/{}", TimeText(self.time))?;
+        }
+        Ok(())
+    }
+}
+
+/// The rules of a zone as a POSIX TZ string, from [`TimeZone::posix`]
+pub struct Posix<'a>(&'a TimeZone);
+
+impl TimeZone {
+    /// The rules as a POSIX TZ string even if the zone was given by name
+    pub fn posix(&self) -> Posix<'_> {
+        Posix(self)
+    }
+}
+
+/// The IANA name if the zone was given by one, so the zone follows any changes to its rules in the
+/// table, otherwise the POSIX TZ string
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.iana {
+            Some(iana) => write!(f, "{iana}"),
+            None => write!(f, "{}", self.posix()),
+        }
+    }
+}
+
+impl fmt::Display for Posix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Posix(zone) = self;
+        write!(f, "{}{}", NameText(&zone.name), TimeText(-zone.offset))?;
+        if let Some(dst) = &zone.dst {
+            write!(f, "{}", NameText(&dst.name))?;
+            if dst.offset != zone.offset + SECONDS_PER_HOUR {
+                write!(f, "{}", TimeText(-dst.offset))?;
+            }
+            write!(f, ",{},{}", dst.start, dst.end)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(s: &str) -> TimeZone {
+        s.parse().unwrap()
+    }
+
+    /// Unix timestamp of a UTC date and time
+    fn utc(year: i32, month: u32, day: u32, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 6, 21), 19_895);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(year_of(utc(2024, 1, 1, 0, 0)), 2024);
+        assert_eq!(year_of(utc(2023, 12, 31, 23, 59)), 2023);
+        assert_eq!(year_of(utc(2024, 2, 29, 12, 0)), 2024);
+        assert_eq!(year_of(-1), 1969);
+    }
+
+    #[test]
+    fn europe() {
+        let cet = zone("CET-1CEST,M3.5.0,M10.5.0/3");
+        assert_eq!(cet.offset, 3600);
+
+        // Clocks go forward at 01:00 UTC on the last Sunday of March, the 31st in 2024
+        assert_eq!(cet.offset_at(utc(2024, 3, 31, 0, 59)), 3600);
+        assert_eq!(cet.offset_at(utc(2024, 3, 31, 1, 0)), 7200);
+        assert_eq!(cet.name_at(utc(2024, 6, 21, 12, 0)), "CEST");
+        // And back at 01:00 UTC on the 27th of October
+        assert_eq!(cet.offset_at(utc(2024, 10, 27, 0, 59)), 7200);
+        assert_eq!(cet.offset_at(utc(2024, 10, 27, 1, 0)), 3600);
+        assert_eq!(cet.name_at(utc(2024, 12, 25, 12, 0)), "CET");
+
+        assert_eq!(cet.next_transition(utc(2024, 6, 21, 12, 0)), Some(utc(2024, 10, 27, 1, 0)));
+        assert_eq!(cet.next_transition(utc(2024, 11, 1, 0, 0)), Some(utc(2025, 3, 30, 1, 0)));
+
+        let uk = zone("GMT0BST,M3.5.0/1,M10.5.0");
+        assert_eq!(uk.offset_at(utc(2024, 3, 31, 0, 59)), 0);
+        assert_eq!(uk.offset_at(utc(2024, 3, 31, 1, 0)), 3600);
+        assert_eq!(uk.offset_at(utc(2024, 10, 27, 1, 0)), 0);
+    }
+
+    #[test]
+    fn southern() {
+        // Sydney, DST from the first Sunday of October to the first Sunday of April
+        let sydney = zone("AEST-10AEDT,M10.1.0,M4.1.0/3");
+        assert_eq!(sydney.offset_at(utc(2024, 1, 15, 0, 0)), 11 * 3600);
+        assert_eq!(sydney.offset_at(utc(2024, 6, 21, 0, 0)), 10 * 3600);
+        assert_eq!(sydney.offset_at(utc(2024, 12, 25, 0, 0)), 11 * 3600);
+        // 03:00 AEDT on the 7th of April is 16:00 UTC on the 6th
+        assert_eq!(sydney.offset_at(utc(2024, 4, 6, 15, 59)), 11 * 3600);
+        assert_eq!(sydney.offset_at(utc(2024, 4, 6, 16, 0)), 10 * 3600);
+    }
+
+    #[test]
+    fn fixed() {
+        let utc_zone = zone("UTC0");
+        assert_eq!(utc_zone.offset_at(0), 0);
+        assert_eq!(utc_zone.next_transition(0), None);
+
+        let india = zone("IST-5:30");
+        assert_eq!(india.offset_at(0), 5 * 3600 + 30 * 60);
+
+        let quoted = zone("<+03>-3");
+        assert_eq!(quoted.name, "+03");
+        assert_eq!(quoted.offset, 3 * 3600);
+    }
+
+    #[test]
+    fn defaults() {
+        // Without rules the US ones apply, second Sunday of March to first Sunday of November
+        let eastern = zone("EST5EDT");
+        let dst = eastern.dst.as_ref().unwrap();
+        assert_eq!(dst.offset, -4 * 3600);
+        assert_eq!(eastern.offset_at(utc(2024, 3, 10, 6, 59)), -5 * 3600);
+        assert_eq!(eastern.offset_at(utc(2024, 3, 10, 7, 0)), -4 * 3600);
+        assert_eq!(eastern.offset_at(utc(2024, 11, 3, 6, 0)), -5 * 3600);
+    }
+
+    #[test]
+    fn julian() {
+        // J60 is always March 1st, 59 counts from 0 so it's February 29th in leap years
+        let j = zone("AAA0BBB,J60,J300");
+        assert_eq!(j.offset_at(utc(2024, 3, 1, 1, 59)), 0);
+        assert_eq!(j.offset_at(utc(2024, 3, 1, 2, 0)), 3600);
+        let zero = zone("AAA0BBB,59,300");
+        assert_eq!(zero.offset_at(utc(2024, 2, 29, 2, 0)), 3600);
+        assert_eq!(zero.offset_at(utc(2023, 2, 28, 2, 0)), 0);
+        assert_eq!(zero.offset_at(utc(2023, 3, 1, 2, 0)), 3600);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!("".parse::<TimeZone>(), Err(ParseTzError::Name));
+        assert_eq!("U0".parse::<TimeZone>(), Err(ParseTzError::Name));
+        assert_eq!("CET".parse::<TimeZone>(), Err(ParseTzError::Offset));
+        assert_eq!("CET25".parse::<TimeZone>(), Err(ParseTzError::Offset));
+        assert_eq!("CET-1CEST,M13.5.0,M10.5.0".parse::<TimeZone>(), Err(ParseTzError::Rule));
+        assert_eq!("CET-1CEST,M3.5.0".parse::<TimeZone>(), Err(ParseTzError::Rule));
+        assert_eq!("CET-1CEST;".parse::<TimeZone>(), Err(ParseTzError::Offset));
+        assert_eq!("CET-1CEST,M3.5.0,M10.5.0x".parse::<TimeZone>(), Err(ParseTzError::TooLong));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in [
+            "CET-1CEST,M3.5.0,M10.5.0/3",
+            "GMT0BST,M3.5.0/1,M10.5.0",
+            "AEST-10AEDT,M10.1.0,M4.1.0/3",
+            "IST-5:30",
+            "<+03>-3",
+            "<-0330>3:30",
+            "NZST-12NZDT,M9.5.0,M4.1.0/3",
+            "AAA0BBB-2,J60/-1:30,300/26",
+        ] {
+            assert_eq!(zone(s).to_string(), s);
+        }
+        // Not given by an IANA name, even though it matches the rules of some
+        assert_eq!(zone("EST5EDT").iana, None);
+        assert_eq!(zone("EST5EDT").to_string(), "EST5EDT,M3.2.0,M11.1.0");
+        assert_eq!(zone("CET-01:00:00CEST-2").to_string(), "CET-1CEST,M3.2.0,M11.1.0");
+    }
+
+    #[test]
+    fn names() {
+        let berlin = zone("Europe/Berlin");
+        assert_eq!(berlin.iana, Some("Europe/Berlin"));
+        assert_eq!(berlin.posix().to_string(), "CET-1CEST,M3.5.0,M10.5.0/3");
+        assert_eq!(berlin.to_string(), "Europe/Berlin");
+        assert_eq!(zone("America/New_York").offset, -5 * 3600);
+        assert_eq!(zone("UTC").offset_at(0), 0);
+        assert_eq!("Europe/Nowhere".parse::<TimeZone>(), Err(ParseTzError::UnknownZone));
+        assert_eq!("Europe/Berlin,M3.5.0".parse::<TimeZone>(), Err(ParseTzError::UnknownZone));
+
+        // Every rule in the table parses and displays the same
+        for (name, rule) in zones::ZONES.iter().map(|&(name, rule)| (name, zones::RULES[rule as usize])) {
+            let zone = rule.parse::<TimeZone>().unwrap_or_else(|e| panic!("{name}: {rule} {e:?}"));
+            assert_eq!(zone.posix().to_string(), rule, "{name}");
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let london = zone("Europe/London");
+        assert_eq!(TimeZone::decode(&london.encode()), Some(london));
+
+        let cet = zone("CET-1CEST,M3.5.0,M10.5.0/3");
+        assert_eq!(TimeZone::decode(&cet.encode()), Some(cet));
+        assert_eq!(TimeZone::decode(&[0xff; TZ_LEN]), None);
+
+        let longest = zone("<ABCDEFGHIJ>-23:59:59<ABCDEFGHIJ>-24,M10.5.0/-167:59:59,M10.5.0/-167:59:59");
+        assert_eq!(TimeZone::decode(&longest.encode()), Some(longest));
+    }
+}